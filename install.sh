@@ -16,7 +16,7 @@ export STATIONS
 export CONCURRENCY
 export SOURCE
 export DATABASE_URL
-stream-check.bin
+exec stream-check.bin --config /etc/stream-check.conf
 EOF
 
 CONFIG_FILE="/etc/stream-check.conf"
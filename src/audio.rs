@@ -0,0 +1,279 @@
+#[cfg(feature = "opus")]
+use audiopus::coder::Decoder as OpusDecoder;
+#[cfg(feature = "opus")]
+use audiopus::packet::Packet as OpusPacket;
+#[cfg(feature = "opus")]
+use audiopus::{Channels, MutSignals, SampleRate};
+#[cfg(feature = "opus")]
+use std::convert::TryFrom;
+use std::io::Cursor;
+use symphonia;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_OPUS};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, Packet, Track};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use stream::Capture;
+
+/// Length of the windows used for silence detection
+const WINDOW_MILLIS: usize = 100;
+/// Samples per channel of the longest opus frame (120ms at 48kHz)
+#[cfg(feature = "opus")]
+const MAX_OPUS_FRAME: usize = 5760;
+
+/// Decoded audio, mixed down to mono
+pub struct Decoded {
+    pub rate: u32,
+    pub samples: Vec<f32>,
+}
+
+impl Decoded {
+    pub fn seconds(&self) -> f32 {
+        if self.rate == 0 {
+            return 0.0;
+        }
+        self.samples.len() as f32 / self.rate as f32
+    }
+}
+
+/// Loudness of the decoded audio in dBFS
+#[derive(Clone, Debug)]
+pub struct Levels {
+    pub rms_db: f32,
+    pub peak_db: f32,
+    /// longest run of windows quieter than the silence threshold
+    pub silence_secs: f32,
+}
+
+/// Decode captured stream data. The format is guessed from the content
+/// type and the data itself. Mp3, aac (adts), ogg vorbis and, with the
+/// opus feature, ogg opus are supported.
+pub fn decode(capture: &Capture) -> Result<Decoded, String> {
+    let content_type = capture.content_type.to_lowercase();
+    let mut hint = Hint::new();
+    if content_type.contains("mpeg") || content_type.contains("mp3") {
+        hint.with_extension("mp3");
+    } else if content_type.contains("aac") {
+        hint.with_extension("aac");
+    } else if content_type.contains("ogg") || content_type.contains("opus") {
+        hint.with_extension("ogg");
+    }
+
+    let source = MediaSourceStream::new(Box::new(Cursor::new(capture.data.clone())), Default::default());
+    let probed = symphonia::default::get_probe()
+        .format(&hint, source, &FormatOptions::default(), &MetadataOptions::default())
+        .map_err(|e| format!("unknown format: {}", e))?;
+    let mut format = probed.format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| String::from("no audio track"))?
+        .clone();
+    if track.codec_params.codec == CODEC_TYPE_OPUS {
+        return decode_opus(format.as_mut(), &track);
+    }
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| format!("no decoder: {}", e))?;
+
+    let mut decoded = Decoded {
+        rate: track.codec_params.sample_rate.unwrap_or(0),
+        samples: vec![],
+    };
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    while let Some(packet) = next_packet(format.as_mut(), &track)? {
+        let audio = match decoder.decode(&packet) {
+            Ok(audio) => audio,
+            Err(Error::DecodeError(e)) => {
+                trace!("Skip broken packet: {}", e);
+                continue;
+            }
+            Err(Error::IoError(_)) => break,
+            Err(e) => return Err(e.to_string()),
+        };
+        let spec = *audio.spec();
+        decoded.rate = spec.rate;
+        let channels = spec.channels.count().max(1);
+        let too_small = match buffer {
+            Some(ref buffer) => buffer.capacity() < audio.capacity() * channels,
+            None => true,
+        };
+        if too_small {
+            buffer = Some(SampleBuffer::new(audio.capacity() as u64, spec));
+        }
+        if let Some(ref mut buffer) = buffer {
+            buffer.copy_interleaved_ref(audio);
+            for frame in buffer.samples().chunks(channels) {
+                decoded.samples.push(frame.iter().sum::<f32>() / channels as f32);
+            }
+        }
+    }
+    if decoded.samples.is_empty() {
+        return Err(String::from("no audio decoded"));
+    }
+    Ok(decoded)
+}
+
+/// Next packet of the track, None at the end of the capture
+fn next_packet(format: &mut dyn FormatReader, track: &Track) -> Result<Option<Packet>, String> {
+    loop {
+        match format.next_packet() {
+            Ok(packet) => {
+                if packet.track_id() == track.id {
+                    return Ok(Some(packet));
+                }
+            }
+            // the capture ends in the middle of a packet
+            Err(Error::IoError(_)) => return Ok(None),
+            Err(Error::ResetRequired) => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// Symphonia has no opus decoder, the packets from the ogg demuxer are
+/// decoded with libopus, always at 48kHz.
+#[cfg(feature = "opus")]
+fn decode_opus(format: &mut dyn FormatReader, track: &Track) -> Result<Decoded, String> {
+    let channels = match track.codec_params.channels.map(|c| c.count()).unwrap_or(2) {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        count => return Err(format!("opus with {} channels is not supported", count)),
+    };
+    let count = channels as usize;
+    let mut decoder = OpusDecoder::new(SampleRate::Hz48000, channels).map_err(|e| e.to_string())?;
+    let mut buffer = vec![0.0f32; MAX_OPUS_FRAME * count];
+    let mut decoded = Decoded {
+        rate: 48_000,
+        samples: vec![],
+    };
+    while let Some(packet) = next_packet(format, track)? {
+        let input = match OpusPacket::try_from(packet.buf()) {
+            Ok(input) => input,
+            Err(_) => continue,
+        };
+        let output = MutSignals::try_from(&mut buffer[..]).map_err(|e| e.to_string())?;
+        let frames = match decoder.decode_float(Some(input), output, false) {
+            Ok(frames) => frames,
+            Err(e) => {
+                trace!("Skip broken packet: {}", e);
+                continue;
+            }
+        };
+        for frame in buffer[..frames * count].chunks(count) {
+            decoded.samples.push(frame.iter().sum::<f32>() / count as f32);
+        }
+    }
+    if decoded.samples.is_empty() {
+        return Err(String::from("no audio decoded"));
+    }
+    Ok(decoded)
+}
+
+#[cfg(not(feature = "opus"))]
+fn decode_opus(_format: &mut dyn FormatReader, _track: &Track) -> Result<Decoded, String> {
+    Err(String::from("opus is not supported, build with the opus feature"))
+}
+
+/// Measure rms and peak level and the longest silence, windows with an
+/// rms level below `silence_db` count as silent.
+pub fn levels(decoded: &Decoded, silence_db: f32) -> Levels {
+    let window = (decoded.rate as usize * WINDOW_MILLIS / 1000).max(1);
+    let mut sum_squares = 0.0f64;
+    let mut peak = 0.0f32;
+    let mut silent_windows = 0;
+    let mut longest_silence = 0;
+    for chunk in decoded.samples.chunks(window) {
+        let chunk_squares: f64 = chunk.iter().map(|s| f64::from(*s) * f64::from(*s)).sum();
+        sum_squares += chunk_squares;
+        peak = chunk.iter().fold(peak, |peak, s| peak.max(s.abs()));
+        let chunk_rms = (chunk_squares / chunk.len() as f64).sqrt() as f32;
+        if to_db(chunk_rms) < silence_db {
+            silent_windows += 1;
+            longest_silence = longest_silence.max(silent_windows);
+        } else {
+            silent_windows = 0;
+        }
+    }
+    let rms = (sum_squares / decoded.samples.len().max(1) as f64).sqrt() as f32;
+    Levels {
+        rms_db: to_db(rms),
+        peak_db: to_db(peak),
+        silence_secs: (longest_silence * WINDOW_MILLIS) as f32 / 1000.0,
+    }
+}
+
+/// dBFS of a linear level, digital silence is clamped to -120
+fn to_db(level: f32) -> f32 {
+    if level <= 0.000_001 {
+        -120.0
+    } else {
+        20.0 * level.log10()
+    }
+}
+
+#[cfg(all(test, feature = "opus"))]
+mod tests {
+    use super::*;
+    use audiopus::coder::Encoder;
+    use audiopus::Application;
+    use std::time::Duration;
+
+    fn ogg_crc(data: &[u8]) -> u32 {
+        let mut crc = 0u32;
+        for byte in data {
+            crc ^= u32::from(*byte) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+            }
+        }
+        crc
+    }
+
+    /// One packet per page, packets are shorter than 255 bytes
+    fn ogg_page(sequence: u32, header_type: u8, granule: u64, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00".to_vec();
+        page.push(header_type);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&1u32.to_le_bytes());
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0, 0, 0, 0, 1, packet.len() as u8]);
+        page.extend_from_slice(packet);
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    #[test]
+    fn decode_ogg_opus() {
+        let mut head = b"OpusHead\x01\x01\x00\x00".to_vec();
+        head.extend_from_slice(&48_000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        let mut data = ogg_page(0, 2, 0, &head);
+        data.extend(ogg_page(1, 0, 0, b"OpusTags\x00\x00\x00\x00\x00\x00\x00\x00"));
+
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Mono, Application::Audio).unwrap();
+        encoder.set_bitrate(audiopus::Bitrate::BitsPerSecond(32_000)).unwrap();
+        let frame: Vec<f32> = (0..960).map(|i| (i as f32 * 0.0575).sin() * 0.5).collect();
+        let mut packet = vec![0u8; 250];
+        for i in 0..50 {
+            let len = encoder.encode_float(&frame, &mut packet).unwrap();
+            data.extend(ogg_page(2 + i, 0, u64::from(i + 1) * 960, &packet[..len]));
+        }
+
+        let capture = Capture {
+            content_type: String::from("audio/ogg"),
+            data,
+            elapsed: Duration::from_secs(1),
+            burst_bytes: 0,
+            icy: false,
+            metadata: vec![],
+        };
+        let decoded = decode(&capture).unwrap();
+        assert_eq!(decoded.rate, 48_000);
+        assert!(decoded.seconds() > 0.9);
+        assert!(levels(&decoded, -60.0).rms_db > -20.0);
+    }
+}
@@ -1,27 +1,60 @@
 use thread;
 use threadpool::ThreadPool;
 
-use av_stream_info_rust;
+use audio;
+use dash;
 use favicon;
+use fingerprint;
+use hls;
+use homepage;
+use nowplaying;
+use probe;
+use region;
+use serverstatus;
+use soak;
 
 use std;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::TryRecvError;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::{Receiver, Sender};
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+
+use config::Config;
+use config::HttpsUpgrade;
+use http::HttpClient;
+use proxy::OutboundProxy;
 
 use models;
+use models::FaviconChange;
+use models::AudioItem;
+use models::BitrateItem;
+use models::FaviconItem;
+use models::FingerprintItem;
+use models::HttpsUpgradeItem;
+use models::NowPlayingItem;
 use models::StationCheckItemNew;
+use models::StationFaviconCheckItem;
+use models::StationHomepageCheckItem;
+use models::StationSoakCheckItem;
 
 use db;
-use mysql;
 
 use colored::*;
 
+use logging;
+use stream;
+use writer::CheckSender;
+
+use suspicious;
+use tls;
+
 fn check_for_change(
     old: &models::StationItem,
     new: &StationCheckItemNew,
-    new_favicon: &str,
 ) -> (bool, String) {
     let mut retval = false;
     let mut result = String::from("");
@@ -55,76 +88,444 @@ fn check_for_change(
         result.push_str(&format!(" codec:{}->{}", old.codec, new.codec));
         retval = true;
     }
+    if let Some(ref bitrate) = new.measured_bitrate {
+        if bitrate.mismatch {
+            result.push_str(&format!(" measured bitrate:{}", bitrate.measured));
+            retval = true;
+        }
+    }
+    if let Some(ref dash) = new.dash_details {
+        if dash.status != "ok" {
+            result.push_str(&format!(" dash:{}", dash.status));
+            retval = true;
+        }
+    }
+    if let Some(ref hls) = new.hls_details {
+        if hls.status != "ok" {
+            result.push_str(&format!(" hls:{}", hls.status));
+            retval = true;
+        }
+    }
+    if new.geo_restricted == Some(true) {
+        let regions = |check_ok: bool| {
+            new.regions
+                .iter()
+                .filter(|region| region.check_ok == check_ok)
+                .map(|region| region.region.as_str())
+                .collect::<Vec<&str>>()
+                .join(",")
+        };
+        result.push_str(&format!(" geo-restricted, works in:{} fails in:{}", regions(true), regions(false)));
+        retval = true;
+    }
+    if let Some(ref status) = new.server_status {
+        if let Some(listeners) = status.listeners {
+            result.push_str(&format!(" listeners:{}", listeners));
+        }
+        if !status.siblings.is_empty() {
+            result.push_str(&format!(" sibling mounts:{}", status.siblings.len()));
+        }
+    }
+    if let Some(ref tls) = new.tls {
+        if tls.status != "valid" {
+            result.push_str(&format!(" tls:{}", tls.status));
+        }
+    }
+    if let Some(ref audio) = new.audio {
+        if audio.silent {
+            result.push_str(&format!(" silent:{:.1}s", audio.silence_secs));
+            retval = true;
+        }
+    }
+    if let Some(ref upgrade) = new.https_upgrade {
+        let action = if upgrade.applied { "upgraded" } else { "upgrade possible" };
+        result.push_str(&format!(" https {}: {}", action, upgrade.new_url));
+        retval = true;
+    }
+    if let Some(ref reason) = new.suspicious {
+        result.push_str(&format!(" suspicious: {}", reason));
+        retval = true;
+    }
     /*if old.urlcache != new.url{
         println!("  url      :{}->{}",old.urlcache,new.url);
         retval = true;
     }*/
-    if old.favicon != new_favicon {
-        result.push_str(&format!(" favicon: {} -> {}", old.favicon, new_favicon));
-        retval = true;
-    }
     if old.check_ok != new.check_ok {
         if new.check_ok {
-            return (retval, result.green().to_string());
+            (retval, result.green().to_string())
         } else {
-            return (retval, result.red().to_string());
+            (retval, result.red().to_string())
         }
     } else {
-        return (retval, result.yellow().to_string());
+        (retval, result.yellow().to_string())
     }
 }
 
 fn update_station(
-    conn: &mysql::Pool,
+    results: &CheckSender,
     old: &models::StationItem,
-    new_item: &StationCheckItemNew,
-    new_favicon: &str,
-    verbosity: u8,
+    new_item: StationCheckItemNew,
 ) {
-    let result = db::insert_check(&conn, &new_item);
-    if let Err(err) = result {
-        println!("Insert check error {}", err);
-    }
-    db::update_station(&conn, &new_item);
-    let (changed, change_str) = check_for_change(&old, &new_item, new_favicon);
+    let (changed, change_str) = check_for_change(old, &new_item);
     if changed {
-        println!("{}", change_str.red());
+        info!("{}", change_str.red());
     } else {
-        if verbosity > 0 {
-            println!("{}", change_str.dimmed());
+        debug!("{}", change_str.dimmed());
+    }
+    // blocks if the writer queue is full
+    if results.send(new_item).is_err() {
+        error!("Check writer is gone, check result lost");
+    }
+}
+
+/// Decide if the favicon of the station should be replaced by the one
+/// found by the favicon check. Favicons are never removed, existing ones
+/// are only replaced if allowed by the config.
+fn favicon_change(config: &Config, old: &models::StationItem, new: &FaviconItem) -> Option<FaviconChange> {
+    if new.url.is_empty() || new.url == old.favicon {
+        return None;
+    }
+    if !old.favicon.is_empty() && !config.favicon_replace {
+        debug!("Keep favicon {}, replacing is disabled", old.favicon);
+        return None;
+    }
+    Some(FaviconChange {
+        old: old.favicon.clone(),
+        new: new.clone(),
+    })
+}
+
+/// Seconds of the stream that have to be read for the enabled checks
+/// of the audio data, 0 if none is enabled.
+fn capture_seconds(config: &Config) -> u64 {
+    let mut seconds = 0;
+    if config.audio_check || config.fingerprint {
+        seconds = config.audio_check_seconds;
+    }
+    if config.bitrate_check {
+        seconds = seconds.max(config.bitrate_check_seconds);
+    }
+    if config.now_playing {
+        seconds = seconds.max(config.now_playing_seconds);
+    }
+    seconds
+}
+
+/// Read the first seconds of the stream once for all checks of the audio
+/// data: measure the bitrate, read the title from icy metadata, measure
+/// the loudness and compute a fingerprint, depending on what is enabled
+/// in the config.
+fn check_capture(config: &Config, item: &mut StationCheckItemNew) {
+    let seconds = capture_seconds(config);
+    let capture_millis = fingerprint::now_millis();
+    let duration = Duration::from_secs(seconds);
+    // enough for 3 mbit/s
+    let max_bytes = 400 * 1024 * seconds.max(1) as usize;
+    let capture = match stream::capture(&item.url, &config.useragent, config.tcp_timeout, config.outbound_proxy.as_ref(), duration, max_bytes) {
+        Ok(capture) => capture,
+        Err(e) => {
+            debug!("Unable to read audio: {}", e);
+            return;
+        }
+    };
+    if config.bitrate_check {
+        item.measured_bitrate = capture.measured_bitrate().map(|measured| {
+            let measured = measured as i32;
+            let difference = (measured - item.bitrate).abs();
+            debug!("Bitrate advertised {} kbit/s, measured {} kbit/s", item.bitrate, measured);
+            BitrateItem {
+                measured,
+                mismatch: item.bitrate > 0 && difference * 100 > item.bitrate * config.bitrate_mismatch_percent as i32,
+            }
+        });
+    }
+    if config.now_playing {
+        let title = capture
+            .metadata
+            .iter()
+            .rev()
+            .filter_map(|metadata| nowplaying::icy_title(metadata))
+            .next();
+        item.now_playing = Some(now_playing_item(if capture.icy { "icy" } else { "" }, title));
+    }
+    if !config.audio_check && !config.fingerprint {
+        return;
+    }
+    let decoded = match audio::decode(&capture) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            debug!("Unable to decode audio ({}): {}", capture.content_type, e);
+            return;
+        }
+    };
+    let levels = audio::levels(&decoded, config.silence_threshold_db);
+    debug!(
+        "Audio {:.1}s rms {:.1} dB peak {:.1} dB silence {:.1}s",
+        decoded.seconds(),
+        levels.rms_db,
+        levels.peak_db,
+        levels.silence_secs
+    );
+    if config.audio_check {
+        item.audio = Some(AudioItem {
+            rms_db: levels.rms_db,
+            peak_db: levels.peak_db,
+            silence_secs: levels.silence_secs,
+            decoded_secs: decoded.seconds(),
+            silent: levels.silence_secs >= config.silence_min_seconds,
+        });
+    }
+    // silence of different stations would match each other
+    if config.fingerprint && levels.rms_db >= config.silence_threshold_db {
+        let frames = fingerprint::compute(&decoded);
+        if !frames.is_empty() {
+            item.fingerprint = Some(FingerprintItem {
+                capture_millis,
+                fingerprint: fingerprint::to_hex(&frames),
+            });
         }
     }
 }
 
-pub fn dbcheck(
-    connection_str: &str,
-    source: &str,
-    concurrency: usize,
-    stations_count: u32,
+fn now_playing_item(metadata_type: &str, title: Option<String>) -> NowPlayingItem {
+    NowPlayingItem {
+        metadata_type: metadata_type.to_string(),
+        has_metadata: title.is_some(),
+        title: title.unwrap_or_default(),
+    }
+}
+
+/// Check a dash manifest that is not understood by the stream probe. Codec
+/// and bitrate are taken from the audio representation with the highest
+/// bandwidth.
+fn check_dash(client: &HttpClient, url: &str, item: &mut StationCheckItemNew) {
+    let result = dash::check(client, url);
+    // not a manifest at all
+    if result.status == "parse_error" {
+        return;
+    }
+    item.dash = true;
+    item.check_ok = result.status == "ok";
+    item.url = url.to_string();
+    let audio = result
+        .representations
+        .iter()
+        .filter(|r| r.content_type.starts_with("audio"))
+        .max_by_key(|r| r.bandwidth)
+        .or_else(|| result.representations.iter().max_by_key(|r| r.bandwidth));
+    if let Some(representation) = audio {
+        item.codec = dash::codec_name(&representation.codecs);
+        item.bitrate = (representation.bandwidth / 1000) as i32;
+    }
+    item.dash_details = Some(result);
+}
+
+/// Probe https variants of a plain http stream. The url of the station
+/// is used if it is http, otherwise the resolved stream url. A variant is
+/// only accepted if it has a valid certificate and the same codec and
+/// bitrate as the http stream.
+fn find_https_upgrade(
+    old: &models::StationItem,
+    new: &StationCheckItemNew,
     useragent: &str,
     timeout: u32,
     max_depth: u8,
-    retries: u8,
-    favicon_checks: bool,
-    verbosity: u8,
+    proxy: Option<&OutboundProxy>,
+    mode: HttpsUpgrade,
+) -> Option<HttpsUpgradeItem> {
+    let old_url = if old.url.starts_with("http://") {
+        &old.url
+    } else if new.url.starts_with("http://") {
+        &new.url
+    } else {
+        return None;
+    };
+    for variant in https_variants(old_url) {
+        match tls::inspect(&variant, timeout, proxy) {
+            Some(ref tls) if tls.status == "valid" => {}
+            _ => {
+                debug!("No valid https for {}", variant);
+                continue;
+            }
+        }
+        let items = probe::check(&variant, useragent, timeout, max_depth, 1, proxy);
+        let same_stream = items
+            .iter()
+            .filter_map(|item| item.as_ref().ok())
+            .next()
+            .is_some_and(|item| item.codec() == new.codec && item.bitrate as i32 == new.bitrate);
+        if same_stream {
+            return Some(HttpsUpgradeItem {
+                old_url: old_url.clone(),
+                new_url: variant,
+                applied: mode == HttpsUpgrade::Apply && old_url == &old.url,
+            });
+        }
+        debug!("{} is not the same stream", variant);
+    }
+    None
+}
+
+/// The url with https on the same port and on the default https port
+fn https_variants(url: &str) -> Vec<String> {
+    let mut variants = vec![];
+    if let Ok(mut url) = Url::parse(url) {
+        // port 443 is the same as the default https port below
+        if url.port().is_some() && url.port() != Some(443) && url.set_scheme("https").is_ok() {
+            variants.push(url.to_string());
+        }
+        if url.set_port(None).is_ok() && url.set_scheme("https").is_ok() {
+            let variant = url.to_string();
+            if !variants.contains(&variant) {
+                variants.push(variant);
+            }
+        }
+    }
+    variants
+}
+
+/// Uuids of the stations whose checks are running, to name the checks
+/// that are abandoned at shutdown
+#[derive(Clone, Default)]
+struct Running(Arc<Mutex<HashSet<String>>>);
+
+/// Removes the station from `Running` when its check ends
+struct RunningCheck {
+    running: Running,
+    uuid: String,
+}
+
+impl Drop for RunningCheck {
+    fn drop(&mut self) {
+        self.running.0.lock().unwrap().remove(&self.uuid);
+    }
+}
+
+impl Running {
+    /// Start the check of a station, None if a shutdown was requested
+    /// while it was queued
+    fn start(&self, shutdown: &AtomicBool, uuid: &str) -> Option<RunningCheck> {
+        if shutdown.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.0.lock().unwrap().insert(uuid.to_string());
+        Some(RunningCheck {
+            running: self.clone(),
+            uuid: uuid.to_string(),
+        })
+    }
+
+    fn uuids(&self) -> Vec<String> {
+        let mut uuids: Vec<String> = self.0.lock().unwrap().iter().cloned().collect();
+        uuids.sort();
+        uuids
+    }
+}
+
+/// Wait for all queued and running checks of the pool. If a shutdown is
+/// requested meanwhile, queued checks are skipped and running checks get
+/// `shutdown_timeout` seconds to finish. Returns the stations whose
+/// checks were abandoned, their results are lost.
+fn wait_for_pool(pool: &ThreadPool, shutdown: &AtomicBool, shutdown_timeout: u64, running: &Running) -> Vec<String> {
+    let mut deadline: Option<Instant> = None;
+    while pool.active_count() + pool.queued_count() > 0 {
+        if deadline.is_none() && shutdown.load(Ordering::SeqCst) {
+            info!(
+                "Shutdown requested, waiting up to {} secs for {} running checks",
+                shutdown_timeout,
+                pool.active_count()
+            );
+            deadline = Some(Instant::now() + Duration::from_secs(shutdown_timeout));
+        }
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                let abandoned = running.uuids();
+                warn!("Shutdown timeout reached, {} checks still running", abandoned.len());
+                for uuid in abandoned.iter() {
+                    warn!("Abandoned check of station {}", uuid);
+                }
+                return abandoned;
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    vec![]
+}
+
+pub fn dbcheck(
+    config: &Config,
+    client: &Arc<HttpClient>,
+    shutdown: &Arc<AtomicBool>,
+    results: &CheckSender,
 ) -> u32 {
-    let conn = db::new(connection_str);
+    let timeout = config.tcp_timeout;
+    let max_depth = config.max_depth;
+    let retries = config.retries;
+    let tls_check = config.tls_check;
+    let https_upgrade = config.https_upgrade;
+    let hls_check = config.hls_check;
+    let dash_check = config.dash_check;
+    let now_playing = config.now_playing;
+    let server_status = config.server_status;
+    let useragent = config.useragent.clone();
+    let capture_seconds = capture_seconds(config);
+    let capture_config = Arc::new(config.clone());
+    // the watchdog has to wait for all probes of a station
+    let mut max_timeout = (retries as u32) * timeout * 2;
+    if tls_check {
+        max_timeout += timeout * 2;
+    }
+    if https_upgrade != HttpsUpgrade::Off {
+        max_timeout += timeout * 8;
+    }
+    if capture_seconds > 0 {
+        max_timeout += capture_seconds as u32 + timeout * 2;
+    }
+    if hls_check || now_playing {
+        // playlists, segment and the reload after the target duration
+        max_timeout += timeout * 8 + 16;
+    }
+    if dash_check {
+        max_timeout += timeout * 6;
+    }
+    if server_status {
+        // stream headers and up to two status pages
+        max_timeout += timeout * 6;
+    }
+    max_timeout += config.regions.len() as u32 * timeout * 2;
+    let stations = db::retry("Fetch stations", db::RETRY_ATTEMPTS, || {
+        let conn = db::new(&config.database_url)?;
+        db::get_stations_to_check(&conn, 24, config.check_stations)
+    });
     let mut checked_count = 0;
-    match conn {
-        Ok(conn) => {
-            let stations = db::get_stations_to_check(&conn, 24, stations_count);
-
-            let pool = ThreadPool::new(concurrency);
+    match stations {
+        Ok(stations) => {
+            let pool = ThreadPool::new(config.concurrency);
+            let running = Running::default();
             for station in stations {
-                checked_count = checked_count + 1;
-                let source = String::from(source);
-                let useragent = String::from(useragent);
-                let conn = conn.clone();
+                checked_count += 1;
+                let source = config.source.clone();
+                let results = results.clone();
+                let shutdown = Arc::clone(shutdown);
+                let running = running.clone();
+                let capture_config = Arc::clone(&capture_config);
+                let useragent = useragent.clone();
+                let client = Arc::clone(client);
                 pool.execute(move || {
-                    let (_, receiver): (Sender<i32>, Receiver<i32>) = channel();
+                    let _running = match running.start(&shutdown, &station.uuid) {
+                        Some(running) => running,
+                        None => return,
+                    };
+                    let _log_context = logging::station_context(&station.uuid, &source, &station.url);
+                    let proxy = capture_config.outbound_proxy.as_ref();
+                    // the watchdog stops once the check sends that it finished
+                    let (finished, receiver): (Sender<i32>, Receiver<i32>) = channel();
                     let station_name = station.name.clone();
-                    let max_timeout = (retries as u32) * timeout * 2;
+                    let station_uuid = station.uuid.clone();
+                    let station_source = source.clone();
+                    let station_url = station.url.clone();
                     thread::spawn(move || {
+                        let _log_context = logging::station_context(&station_uuid, &station_source, &station_url);
                         for _ in 0..max_timeout {
                             thread::sleep(Duration::from_secs(1));
                             let o = receiver.try_recv();
@@ -140,7 +541,7 @@ pub fn dbcheck(
                                 },
                             }
                         }
-                        println!("Still not finished: {}", station_name);
+                        error!("Still not finished: {}", station_name);
                         std::process::exit(0x0100);
                     });
                     let mut new_item: StationCheckItemNew = StationCheckItemNew {
@@ -149,51 +550,417 @@ pub fn dbcheck(
                         codec: "".to_string(),
                         bitrate: 0,
                         hls: false,
+                        dash: false,
                         check_ok: false,
                         url: "".to_string(),
+                        suspicious: None,
+                        tls: None,
+                        https_upgrade: None,
+                        audio: None,
+                        fingerprint: None,
+                        measured_bitrate: None,
+                        hls_details: None,
+                        dash_details: None,
+                        now_playing: None,
+                        server_status: None,
+                        regions: vec![],
+                        geo_restricted: None,
                     };
                     let items =
-                        av_stream_info_rust::check(&station.url, timeout, max_depth, retries);
-                    for item in items.iter() {
-                        match item {
-                            &Ok(ref item) => {
-                                let mut codec = item.CodecAudio.clone();
-                                if let Some(ref video) = item.CodecVideo {
-                                    codec.push_str(",");
-                                    codec.push_str(&video);
-                                }
-                                new_item = StationCheckItemNew {
-                                    station_uuid: station.uuid.clone(),
-                                    source: source.clone(),
-                                    codec: codec,
-                                    bitrate: item.Bitrate as i32,
-                                    hls: item.Hls,
-                                    check_ok: true,
-                                    url: item.Url.clone(),
-                                };
-                            }
-                            &Err(_) => {}
+                        probe::check(&station.url, &useragent, timeout, max_depth, retries, proxy);
+                    for item in items.iter().flatten() {
+                        new_item = StationCheckItemNew {
+                            station_uuid: station.uuid.clone(),
+                            source: source.clone(),
+                            codec: item.codec(),
+                            bitrate: item.bitrate as i32,
+                            hls: item.hls,
+                            dash: false,
+                            check_ok: true,
+                            url: item.url.clone(),
+                            suspicious: None,
+                            tls: None,
+                            https_upgrade: None,
+                            audio: None,
+                            fingerprint: None,
+                            measured_bitrate: None,
+                            hls_details: None,
+                            dash_details: None,
+                            now_playing: None,
+                            server_status: None,
+                            regions: vec![],
+                            geo_restricted: None,
+                        };
+                    }
+                    if !new_item.check_ok && dash_check && dash::is_manifest_url(&station.url) {
+                        check_dash(&client, &station.url, &mut new_item);
+                    }
+                    if new_item.check_ok && (hls_check || now_playing) && new_item.hls {
+                        let result = hls::check(&client, &new_item.url);
+                        if now_playing {
+                            let metadata_type = if result.now_playing.is_some() { "id3" } else { "" };
+                            new_item.now_playing = Some(now_playing_item(metadata_type, result.now_playing.clone()));
+                        }
+                        if hls_check {
+                            new_item.check_ok = result.status == "ok";
+                            new_item.hls_details = Some(result);
                         }
                     }
-                    if favicon_checks {
-                        let new_favicon = favicon::check(
-                            &station.homepage,
-                            &station.favicon,
-                            verbosity,
-                            &useragent,
-                            timeout,
-                        );
-                        update_station(&conn, &station, &new_item, &new_favicon, verbosity);
-                    } else {
-                        update_station(&conn, &station, &new_item, &station.favicon, verbosity);
+                    new_item.suspicious = suspicious::check(&station, &new_item);
+                    if new_item.suspicious.is_some() {
+                        // not accepted, the station keeps its last state
+                        new_item.check_ok = false;
+                    } else if !capture_config.regions.is_empty() {
+                        let url = if new_item.check_ok { &new_item.url } else { &station.url };
+                        new_item.regions = region::check(&capture_config.regions, url, &useragent, timeout, max_depth);
+                        new_item.geo_restricted = region::geo_restricted(new_item.check_ok, &new_item.regions);
                     }
+                    if new_item.check_ok && capture_seconds > 0 && !new_item.hls && !new_item.dash {
+                        check_capture(&capture_config, &mut new_item);
+                    }
+                    if new_item.check_ok && server_status && !new_item.hls && !new_item.dash {
+                        new_item.server_status = serverstatus::check(&client, &new_item.url);
+                    }
+                    if new_item.check_ok && https_upgrade != HttpsUpgrade::Off {
+                        new_item.https_upgrade = find_https_upgrade(&station, &new_item, &useragent, timeout, max_depth, proxy, https_upgrade);
+                    }
+                    if tls_check {
+                        let url = if new_item.url.is_empty() { &station.url } else { &new_item.url };
+                        new_item.tls = tls::inspect(url, timeout, proxy);
+                    }
+                    update_station(&results, &station, new_item);
+                    let _ = finished.send(0);
                 });
             }
-            pool.join();
+            wait_for_pool(&pool, shutdown, config.shutdown_timeout, &running);
         }
         Err(e) => {
-            println!("Database connection error {}", e);
+            error!("Unable to fetch stations to check: {}", e);
         }
     }
     checked_count
 }
+
+/// Checks that run on their own schedule, next to the stream checks
+#[derive(Clone, Copy, Debug)]
+pub enum Task {
+    Favicon,
+    Homepage,
+    Soak,
+}
+
+impl Task {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Task::Favicon => "favicon",
+            Task::Homepage => "homepage",
+            Task::Soak => "soak",
+        }
+    }
+
+    pub fn enabled(&self, config: &Config) -> bool {
+        match *self {
+            Task::Favicon => config.favicon,
+            Task::Homepage => config.homepage,
+            Task::Soak => config.soak,
+        }
+    }
+
+    /// Pause if the last batch had nothing to do
+    pub fn pause_seconds(&self, config: &Config) -> u64 {
+        match *self {
+            Task::Favicon => config.favicon_pause_seconds,
+            Task::Homepage => config.homepage_pause_seconds,
+            Task::Soak => config.soak_pause_seconds,
+        }
+    }
+
+    /// Run one batch, returns the number of checked stations
+    pub fn run(&self, config: &Config, client: &Arc<HttpClient>, shutdown: &Arc<AtomicBool>) -> u32 {
+        match *self {
+            Task::Favicon => faviconcheck(config, client, shutdown),
+            Task::Homepage => homepagecheck(config, client, shutdown),
+            Task::Soak => soakcheck(config, shutdown),
+        }
+    }
+}
+
+/// Check the favicons of stations that were not checked for
+/// `favicon_interval` hours, independent of the stream checks. Results are
+/// saved together after the batch. Returns the number of checked stations.
+fn faviconcheck(config: &Config, client: &Arc<HttpClient>, shutdown: &Arc<AtomicBool>) -> u32 {
+    let stations = db::retry("Fetch favicon stations", db::RETRY_ATTEMPTS, || {
+        let conn = db::new(&config.database_url)?;
+        db::get_stations_to_check_favicon(&conn, config.favicon_interval, config.favicon_stations)
+    });
+    let stations = match stations {
+        Ok(stations) => stations,
+        Err(e) => {
+            error!("Unable to fetch stations for favicon check: {}", e);
+            return 0;
+        }
+    };
+    let checked_count = stations.len() as u32;
+    let pool = ThreadPool::new(config.favicon_concurrency);
+    let running = Running::default();
+    let job_config = Arc::new(config.clone());
+    let (sender, receiver) = channel();
+    for station in stations {
+        let job_config = Arc::clone(&job_config);
+        let client = Arc::clone(client);
+        let shutdown = Arc::clone(shutdown);
+        let sender = sender.clone();
+        let running = running.clone();
+        pool.execute(move || {
+            let _running = match running.start(&shutdown, &station.uuid) {
+                Some(running) => running,
+                None => return,
+            };
+            let _log_context = logging::station_context(&station.uuid, &job_config.source, &station.url);
+            let new_favicon = favicon::check(&job_config, &client, &station.homepage, &station.favicon);
+            let change = favicon_change(&job_config, &station, &new_favicon);
+            let unchanged = !new_favicon.url.is_empty() && new_favicon.url == station.favicon;
+            let favicon_used = change.is_some() || unchanged;
+            let mirror = if favicon_used && !new_favicon.mirror.is_empty() && new_favicon.mirror != station.favicon_mirror {
+                Some(new_favicon.mirror.clone())
+            } else {
+                None
+            };
+            if let Some(ref change) = change {
+                info!("{}", favicon_change_str(&station, change).red());
+            }
+            let _ = sender.send(StationFaviconCheckItem {
+                station_uuid: station.uuid.clone(),
+                source: job_config.source.clone(),
+                favicon_change: change,
+                favicon_mirror: mirror,
+                favicon_size: if unchanged { Some((new_favicon.width, new_favicon.height)) } else { None },
+            });
+        });
+    }
+    drop(sender);
+    wait_for_pool(&pool, shutdown, config.shutdown_timeout, &running);
+
+    let items: Vec<StationFaviconCheckItem> = receiver.try_iter().collect();
+    let result = db::retry("Save favicon checks", db::RETRY_ATTEMPTS, || {
+        let conn = db::new(&config.database_url)?;
+        db::save_favicon_checks(&conn, &items)
+    });
+    if let Err(e) = result {
+        error!("Save favicon checks error, dropping {} results: {}", items.len(), e);
+    }
+    checked_count
+}
+
+/// Check the homepages of stations that were not checked for
+/// `homepage_interval` hours. Returns the number of checked stations.
+fn homepagecheck(config: &Config, client: &Arc<HttpClient>, shutdown: &Arc<AtomicBool>) -> u32 {
+    let stations = db::retry("Fetch homepage stations", db::RETRY_ATTEMPTS, || {
+        let conn = db::new(&config.database_url)?;
+        db::get_stations_to_check_homepage(&conn, config.homepage_interval, config.homepage_stations, &config.source)
+    });
+    let stations = match stations {
+        Ok(stations) => stations,
+        Err(e) => {
+            error!("Unable to fetch stations for homepage check: {}", e);
+            return 0;
+        }
+    };
+    let checked_count = stations.len() as u32;
+    let pool = ThreadPool::new(config.homepage_concurrency);
+    let running = Running::default();
+    let (sender, receiver) = channel();
+    for station in stations {
+        let source = config.source.clone();
+        let client = Arc::clone(client);
+        let shutdown = Arc::clone(shutdown);
+        let sender = sender.clone();
+        let running = running.clone();
+        pool.execute(move || {
+            let _running = match running.start(&shutdown, &station.uuid) {
+                Some(running) => running,
+                None => return,
+            };
+            let _log_context = logging::station_context(&station.uuid, &source, &station.homepage);
+            let result = homepage::check(&client, &station.homepage);
+            if result.status != "ok" {
+                info!("{}", format!("'{}' homepage {}: {} {}", station.name, result.status, result.http_status, result.final_url).yellow());
+            } else {
+                debug!("'{}' homepage ok: {}", station.name, result.final_url);
+            }
+            let _ = sender.send(StationHomepageCheckItem {
+                station_uuid: station.uuid.clone(),
+                source,
+                homepage: result,
+            });
+        });
+    }
+    drop(sender);
+    wait_for_pool(&pool, shutdown, config.shutdown_timeout, &running);
+
+    let items: Vec<StationHomepageCheckItem> = receiver.try_iter().collect();
+    let result = db::retry("Save homepage checks", db::RETRY_ATTEMPTS, || {
+        let conn = db::new(&config.database_url)?;
+        db::save_homepage_checks(&conn, &items)
+    });
+    if let Err(e) = result {
+        error!("Save homepage checks error, dropping {} results: {}", items.len(), e);
+    }
+    checked_count
+}
+
+/// Listen to working streams that were not soaked for `soak_interval`
+/// hours. Returns the number of checked stations.
+fn soakcheck(config: &Config, shutdown: &Arc<AtomicBool>) -> u32 {
+    let stations = db::retry("Fetch soak stations", db::RETRY_ATTEMPTS, || {
+        let conn = db::new(&config.database_url)?;
+        db::get_stations_to_check_soak(&conn, config.soak_interval, config.soak_stations, &config.source)
+    });
+    let stations = match stations {
+        Ok(stations) => stations,
+        Err(e) => {
+            error!("Unable to fetch stations for soak check: {}", e);
+            return 0;
+        }
+    };
+    let checked_count = stations.len() as u32;
+    let pool = ThreadPool::new(config.soak_concurrency);
+    let running = Running::default();
+    let job_config = Arc::new(config.clone());
+    let (sender, receiver) = channel();
+    for station in stations {
+        let job_config = Arc::clone(&job_config);
+        let shutdown = Arc::clone(shutdown);
+        let sender = sender.clone();
+        let running = running.clone();
+        pool.execute(move || {
+            let _running = match running.start(&shutdown, &station.uuid) {
+                Some(running) => running,
+                None => return,
+            };
+            let url = if station.urlcache.is_empty() { &station.url } else { &station.urlcache };
+            let _log_context = logging::station_context(&station.uuid, &job_config.source, url);
+            let result = soak::listen(
+                url,
+                &job_config.useragent,
+                job_config.tcp_timeout,
+                job_config.outbound_proxy.as_ref(),
+                Duration::from_secs(job_config.soak_seconds),
+                Duration::from_millis(job_config.soak_gap_millis),
+            );
+            let line = format!(
+                "'{}' soak score {}: {} underruns, {:.1}s stalled, {} disconnects, {} reconnects",
+                station.name, result.score, result.underruns, result.stalled_secs, result.disconnects, result.reconnects
+            );
+            if result.score < 100 {
+                info!("{}", line.yellow());
+            } else {
+                debug!("{}", line);
+            }
+            let _ = sender.send(StationSoakCheckItem {
+                station_uuid: station.uuid.clone(),
+                source: job_config.source.clone(),
+                soak: result,
+            });
+        });
+    }
+    drop(sender);
+    wait_for_pool(&pool, shutdown, config.shutdown_timeout, &running);
+
+    let items: Vec<StationSoakCheckItem> = receiver.try_iter().collect();
+    let result = db::retry("Save soak checks", db::RETRY_ATTEMPTS, || {
+        let conn = db::new(&config.database_url)?;
+        db::save_soak_checks(&conn, &items)
+    });
+    if let Err(e) = result {
+        error!("Save soak checks error, dropping {} results: {}", items.len(), e);
+    }
+    checked_count
+}
+
+fn favicon_change_str(old: &models::StationItem, change: &FaviconChange) -> String {
+    let mut result = format!("'{}' favicon: {} -> {}", old.name, change.old, change.new.url);
+    if change.new.width > 0 {
+        result.push_str(&format!(" ({}x{} {})", change.new.width, change.new.height, change.new.format));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn https_variants_default_port() {
+        assert_eq!(https_variants("http://example.com/live"), vec!["https://example.com/live"]);
+        assert_eq!(https_variants("http://example.com:80/live?a=1"), vec!["https://example.com/live?a=1"]);
+    }
+
+    #[test]
+    fn https_variants_custom_port() {
+        assert_eq!(
+            https_variants("http://example.com:8000/live"),
+            vec!["https://example.com:8000/live", "https://example.com/live"]
+        );
+        assert_eq!(https_variants("http://example.com:443/live"), vec!["https://example.com/live"]);
+    }
+
+    #[test]
+    fn https_variants_invalid() {
+        assert!(https_variants("not a url").is_empty());
+    }
+
+    /// Run a check on the pool that waits for `release` to be set
+    fn queue(pool: &ThreadPool, running: &Running, shutdown: &Arc<AtomicBool>, uuid: &'static str, release: &Arc<AtomicBool>, executed: &Arc<Mutex<Vec<String>>>) {
+        let (running, shutdown, release, executed) = (running.clone(), Arc::clone(shutdown), Arc::clone(release), Arc::clone(executed));
+        pool.execute(move || {
+            let _running = match running.start(&shutdown, uuid) {
+                Some(running) => running,
+                None => return,
+            };
+            executed.lock().unwrap().push(uuid.to_string());
+            while !release.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+    }
+
+    #[test]
+    fn queued_checks_are_skipped_at_shutdown() {
+        let pool = ThreadPool::new(1);
+        let running = Running::default();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let release = Arc::new(AtomicBool::new(false));
+        let executed = Arc::new(Mutex::new(vec![]));
+        queue(&pool, &running, &shutdown, "first", &release, &executed);
+        queue(&pool, &running, &shutdown, "second", &release, &executed);
+        while running.uuids().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        shutdown.store(true, Ordering::SeqCst);
+        release.store(true, Ordering::SeqCst);
+
+        assert!(wait_for_pool(&pool, &shutdown, 5, &running).is_empty());
+        assert_eq!(*executed.lock().unwrap(), vec!["first"]);
+        assert!(running.uuids().is_empty());
+    }
+
+    #[test]
+    fn running_checks_are_named_after_the_timeout() {
+        let pool = ThreadPool::new(2);
+        let running = Running::default();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let release = Arc::new(AtomicBool::new(false));
+        let executed = Arc::new(Mutex::new(vec![]));
+        queue(&pool, &running, &shutdown, "slow", &release, &executed);
+        while running.uuids().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        shutdown.store(true, Ordering::SeqCst);
+
+        assert_eq!(wait_for_pool(&pool, &shutdown, 0, &running), vec!["slow"]);
+        release.store(true, Ordering::SeqCst);
+        pool.join();
+        assert!(running.uuids().is_empty());
+    }
+}
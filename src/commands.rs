@@ -0,0 +1,178 @@
+use clap::ArgMatches;
+
+use config::Config;
+use db;
+use db::DbError;
+use fingerprint;
+use models::FaviconRevert;
+
+/// Run a maintenance subcommand. Output goes to stdout, so it can be used
+/// in scripts.
+pub fn run(config: &Config, name: &str, matches: &ArgMatches) -> Result<(), DbError> {
+    let pool = db::new(&config.database_url)?;
+    match name {
+        "favicon-history" => {
+            let station_uuid = matches.value_of("STATIONUUID").unwrap();
+            let history = db::get_favicon_history(&pool, station_uuid)?;
+            for item in history {
+                println!(
+                    "{}\t{}\t{}\t{} -> {}\t{}x{}",
+                    item.id,
+                    item.change_time,
+                    item.source,
+                    item.old_favicon,
+                    item.new_favicon,
+                    item.width,
+                    item.height
+                );
+            }
+        }
+        "favicon-revert" => {
+            let id: i32 = matches
+                .value_of("ID")
+                .unwrap()
+                .parse()
+                .expect("ID is not i32");
+            match db::revert_favicon(&pool, id)? {
+                FaviconRevert::Reverted(item) => {
+                    println!(
+                        "{}: favicon {} -> {}, pinned",
+                        item.station_uuid, item.old_favicon, item.new_favicon
+                    );
+                }
+                FaviconRevert::NotFound => {
+                    println!("No favicon history entry with id {}", id);
+                }
+                FaviconRevert::Changed(current) => {
+                    println!("Favicon was changed again since entry {}, it is {} now", id, current);
+                }
+            }
+        }
+        "favicon-unpin" => {
+            let station_uuid = matches.value_of("STATIONUUID").unwrap();
+            if db::unpin_favicon(&pool, station_uuid)? {
+                println!("{}: favicon unpinned", station_uuid);
+            } else {
+                println!("No station with uuid {}", station_uuid);
+            }
+        }
+        "homepage-report" => {
+            let report = db::get_homepage_report(&pool, matches.value_of("STATUS"))?;
+            for item in report {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    item.station_uuid,
+                    item.status,
+                    item.http_status,
+                    item.check_time,
+                    item.name,
+                    item.homepage,
+                    item.final_url,
+                    item.title
+                );
+            }
+        }
+        "suspicious-report" => {
+            for item in db::get_suspicious_report(&pool)? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    item.station_uuid, item.reason, item.urlcache, item.url, item.name
+                );
+            }
+        }
+        "suspicious-clear" => {
+            let station_uuid = matches.value_of("STATIONUUID").unwrap();
+            if db::clear_suspicious(&pool, station_uuid)? {
+                println!("{}: cleared, the next check accepts its stream", station_uuid);
+            } else {
+                println!("Station {} is not marked as suspicious", station_uuid);
+            }
+        }
+        "tls-report" => {
+            let days: u32 = match matches.value_of("DAYS") {
+                Some(days) => days.parse().expect("DAYS is not u32"),
+                None => config.tls_expiry_days,
+            };
+            for item in db::get_tls_report(&pool, days)? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    item.station_uuid,
+                    item.target,
+                    item.status,
+                    item.not_after,
+                    item.host,
+                    item.issuer,
+                    item.name,
+                    item.error
+                );
+            }
+        }
+        "https-upgrades" => {
+            for item in db::get_https_upgrades(&pool, matches.is_present("all"))? {
+                println!(
+                    "{}\t{}\t{}\t{} -> {}\t{}",
+                    item.station_uuid,
+                    item.check_time,
+                    if item.applied { "applied" } else { "suggested" },
+                    item.old_url,
+                    item.new_url,
+                    item.name
+                );
+            }
+        }
+        "soak-report" => {
+            let max_score: u32 = match matches.value_of("SCORE") {
+                Some(score) => score.parse().expect("SCORE is not u32"),
+                None => 80,
+            };
+            for item in db::get_soak_report(&pool, max_score)? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    item.station_uuid,
+                    item.score,
+                    item.underruns,
+                    item.disconnects,
+                    item.failed_reconnects,
+                    item.check_time,
+                    item.name,
+                    item.url
+                );
+            }
+        }
+        "duplicates" => {
+            let since = match matches.value_of("HOURS") {
+                Some(hours) => {
+                    let hours: i64 = hours.parse().expect("HOURS is not i64");
+                    fingerprint::now_millis() - hours * 3600 * 1000
+                }
+                None => 0,
+            };
+            let min_similarity: f32 = match matches.value_of("similarity") {
+                Some(value) => value.parse().expect("similarity is not f32"),
+                None => config.duplicate_min_similarity,
+            };
+            let fingerprints = db::get_fingerprints(&pool, since)?;
+            let items: Vec<(String, i64, Vec<u16>)> = fingerprints
+                .iter()
+                .map(|item| (item.station_uuid.clone(), item.capture_millis, fingerprint::from_hex(&item.fingerprint)))
+                .collect();
+            let clusters = fingerprint::clusters(&items, fingerprint::MAX_OFFSET_MILLIS, min_similarity);
+            for (index, (uuids, similarity)) in clusters.iter().enumerate() {
+                for uuid in uuids {
+                    if let Some(item) = fingerprints.iter().find(|item| &item.station_uuid == uuid) {
+                        println!(
+                            "{}\t{:.2}\t{}\t{}\t{}",
+                            index + 1,
+                            similarity,
+                            item.station_uuid,
+                            item.name,
+                            item.url
+                        );
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,523 @@
+use chrono::DateTime;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use hls;
+use http::HttpClient;
+use models::DashItem;
+use models::DashRepresentationItem;
+
+/// Manifests larger than this are not parsed
+const MAX_MANIFEST_BYTES: u64 = 1024 * 1024;
+/// Only the start of a segment is read to see that it is delivered
+const MAX_SEGMENT_BYTES: u64 = 256 * 1024;
+/// Segments behind the calculated live edge that are requested, the
+/// newest one may not be published yet
+const LIVE_EDGE_DISTANCE: u64 = 2;
+
+/// True if the url looks like a dash manifest
+pub fn is_manifest_url(url: &str) -> bool {
+    Url::parse(url)
+        .map(|url| url.path().to_lowercase().ends_with(".mpd"))
+        .unwrap_or(false)
+}
+
+/// Validate a dash manifest: list its representations and fetch the
+/// initialization and one media segment of the audio representation
+/// with the lowest bandwidth (the newest segment of live streams).
+pub fn check(client: &HttpClient, url: &str) -> DashItem {
+    let mut item = DashItem {
+        status: String::from("ok"),
+        live: false,
+        representations: vec![],
+        segment_url: String::from(""),
+        segment_http_status: 0,
+        error: String::from(""),
+    };
+    if let Err((status, error)) = validate(client, url, &mut item) {
+        debug!("DASH {} {}: {}", status, url, error);
+        item.status = status.to_string();
+        item.error = error;
+    }
+    item
+}
+
+type Failure = (&'static str, String);
+
+/// SegmentTemplate of an adaptation set or representation
+#[derive(Clone, Debug)]
+struct Template {
+    media: String,
+    initialization: String,
+    start_number: u64,
+    timescale: u64,
+    duration: u64,
+    /// (t, d, r) of the S elements of a SegmentTimeline
+    timeline: Vec<(Option<u64>, u64, i64)>,
+}
+
+#[derive(Clone, Debug)]
+struct Representation {
+    item: DashRepresentationItem,
+    base_url: String,
+    template: Option<Template>,
+    segment_list: Vec<String>,
+}
+
+struct Manifest {
+    live: bool,
+    /// availabilityStartTime in unix seconds
+    availability_start: Option<i64>,
+    representations: Vec<Representation>,
+}
+
+fn validate(client: &HttpClient, url: &str, item: &mut DashItem) -> Result<(), Failure> {
+    let (status, final_url, bytes) = client
+        .get(url, |res| {
+            let status = res.status().as_u16();
+            let final_url = res.url().to_string();
+            let mut bytes = vec![];
+            if res.status().is_success() {
+                res.take(MAX_MANIFEST_BYTES)
+                    .read_to_end(&mut bytes)
+                    .map_err(|e| e.to_string())?;
+            }
+            Ok((status, final_url, bytes))
+        })
+        .map_err(|e| ("manifest_error", e))?;
+    match status {
+        200..=299 => {}
+        401 | 403 | 410 => return Err((hls::expired_or_forbidden(url), format!("http status {} for {}", status, url))),
+        _ => return Err(("manifest_error", format!("http status {} for {}", status, url))),
+    }
+
+    let manifest = parse(&bytes, &final_url).map_err(|e| ("parse_error", e))?;
+    item.live = manifest.live;
+    item.representations = manifest.representations.iter().map(|r| r.item.clone()).collect();
+    let representation = manifest
+        .representations
+        .iter()
+        .filter(|r| r.item.content_type.starts_with("audio"))
+        .min_by_key(|r| r.item.bandwidth)
+        .or_else(|| manifest.representations.iter().min_by_key(|r| r.item.bandwidth))
+        .ok_or_else(|| ("no_representations", String::from("manifest without representations")))?;
+
+    let (init_url, segment_url) = segment_urls(&manifest, representation);
+    if let Some(init_url) = init_url {
+        fetch_segment(client, &init_url, item)?;
+    }
+    fetch_segment(client, &segment_url, item)
+}
+
+fn fetch_segment(client: &HttpClient, url: &str, item: &mut DashItem) -> Result<(), Failure> {
+    item.segment_url = url.to_string();
+    let (status, bytes) = client
+        .get(url, |res| {
+            let status = res.status().as_u16();
+            let mut bytes = vec![];
+            if res.status().is_success() {
+                res.take(MAX_SEGMENT_BYTES)
+                    .read_to_end(&mut bytes)
+                    .map_err(|e| e.to_string())?;
+            }
+            Ok((status, bytes))
+        })
+        .map_err(|e| ("segment_error", e))?;
+    item.segment_http_status = u32::from(status);
+    match status {
+        200..=299 => {}
+        404 => return Err(("segment_not_found", url.to_string())),
+        401 | 403 | 410 => return Err((hls::expired_or_forbidden(url), format!("http status {} for {}", status, url))),
+        _ => return Err(("segment_error", format!("http status {} for {}", status, url))),
+    }
+    if bytes.is_empty() {
+        return Err(("segment_error", format!("empty segment {}", url)));
+    }
+    Ok(())
+}
+
+/// Initialization segment (if any) and the media segment to fetch
+fn segment_urls(manifest: &Manifest, representation: &Representation) -> (Option<String>, String) {
+    let base = &representation.base_url;
+    if let Some(ref template) = representation.template {
+        let (number, time) = segment_position(manifest, template);
+        let init = if template.initialization.is_empty() {
+            None
+        } else {
+            Some(hls::join(base, &fill_template(&template.initialization, &representation.item, number, time)))
+        };
+        return (init, hls::join(base, &fill_template(&template.media, &representation.item, number, time)));
+    }
+    let listed = if manifest.live {
+        representation.segment_list.last()
+    } else {
+        representation.segment_list.first()
+    };
+    match listed {
+        Some(segment) => (None, hls::join(base, segment)),
+        // SegmentBase, the representation is a single file
+        None => (None, base.clone()),
+    }
+}
+
+/// Number and time of the first segment, or of a segment close to the
+/// live edge for live streams
+fn segment_position(manifest: &Manifest, template: &Template) -> (u64, u64) {
+    if !template.timeline.is_empty() {
+        if !manifest.live {
+            return (template.start_number, template.timeline[0].0.unwrap_or(0));
+        }
+        // repeats can be huge, the last segment is computed instead of
+        // listing all of them
+        let mut number = template.start_number;
+        let mut time = 0u64;
+        let mut last = (number, time);
+        for &(t, d, r) in template.timeline.iter() {
+            if let Some(t) = t {
+                time = t;
+            }
+            let repeats = r.max(0) as u64;
+            last = (number.saturating_add(repeats), time.saturating_add(d.saturating_mul(repeats)));
+            number = last.0.saturating_add(1);
+            time = last.1.saturating_add(d);
+        }
+        return last;
+    }
+    if !manifest.live || template.duration == 0 {
+        return (template.start_number, 0);
+    }
+    let start = match manifest.availability_start {
+        Some(start) => start,
+        None => return (template.start_number, 0),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let elapsed = (now - start).max(0) as u64 * template.timescale.max(1);
+    let count = (elapsed / template.duration).saturating_sub(LIVE_EDGE_DISTANCE);
+    (template.start_number + count, count * template.duration)
+}
+
+/// Replace $RepresentationID$, $Bandwidth$, $Number$ and $Time$ of a
+/// segment template, with an optional printf width like $Number%05d$.
+fn fill_template(template: &str, representation: &DashRepresentationItem, number: u64, time: u64) -> String {
+    let mut result = String::new();
+    let mut parts = template.split('$');
+    if let Some(first) = parts.next() {
+        result.push_str(first);
+    }
+    let mut is_identifier = true;
+    for part in parts {
+        if !is_identifier {
+            result.push_str(part);
+            is_identifier = true;
+            continue;
+        }
+        is_identifier = false;
+        let (name, width) = match part.find('%') {
+            Some(index) => {
+                let width: usize = part[index + 1..]
+                    .trim_start_matches('0')
+                    .trim_end_matches('d')
+                    .parse()
+                    .unwrap_or(0);
+                (&part[..index], width)
+            }
+            None => (part, 0),
+        };
+        let value = match name {
+            "" => String::from("$"),
+            "RepresentationID" => representation.id.clone(),
+            "Bandwidth" => representation.bandwidth.to_string(),
+            "Number" => number.to_string(),
+            "Time" => time.to_string(),
+            _ => format!("${}$", part),
+        };
+        result.push_str(&format!("{:0>width$}", value, width = width));
+    }
+    result
+}
+
+fn attributes(e: &BytesStart) -> Result<HashMap<Vec<u8>, String>, String> {
+    let mut attrs: HashMap<Vec<u8>, String> = HashMap::new();
+    for attr in e.attributes() {
+        let attr = attr.map_err(|e| e.to_string())?;
+        let value = String::from_utf8_lossy(&attr.value).to_string();
+        attrs.insert(attr.key.to_vec(), value);
+    }
+    Ok(attrs)
+}
+
+fn attribute_number(attrs: &HashMap<Vec<u8>, String>, name: &[u8]) -> Option<u64> {
+    attrs.get(name).and_then(|v| v.trim().parse().ok())
+}
+
+fn parse_template(attrs: &HashMap<Vec<u8>, String>) -> Template {
+    Template {
+        media: attrs.get(&b"media"[..]).cloned().unwrap_or_default(),
+        initialization: attrs.get(&b"initialization"[..]).cloned().unwrap_or_default(),
+        start_number: attribute_number(attrs, b"startNumber").unwrap_or(1),
+        timescale: attribute_number(attrs, b"timescale").unwrap_or(1),
+        duration: attribute_number(attrs, b"duration").unwrap_or(0),
+        timeline: vec![],
+    }
+}
+
+/// Parse the parts of a manifest needed to find segments. Base urls are
+/// resolved against `url`. Only the first period is used.
+fn parse(bytes: &[u8], url: &str) -> Result<Manifest, String> {
+    let mut reader = Reader::from_reader(bytes);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut manifest: Option<Manifest> = None;
+    // base url of MPD, Period, AdaptationSet and Representation
+    let mut bases: Vec<String> = vec![url.to_string()];
+    let mut set_attrs: HashMap<Vec<u8>, String> = HashMap::new();
+    let mut set_template: Option<Template> = None;
+    let mut representation: Option<Representation> = None;
+    let mut periods = 0;
+    loop {
+        let event = reader.read_event(&mut buf);
+        let (e, empty) = match event {
+            Ok(Event::Start(ref e)) => (e.clone().into_owned(), false),
+            Ok(Event::Empty(ref e)) => (e.clone().into_owned(), true),
+            Ok(Event::End(ref e)) => {
+                match e.local_name() {
+                    b"Representation" => {
+                        let base = bases.pop().unwrap_or_default();
+                        if let (Some(rep), Some(ref mut manifest)) = (representation.take(), manifest.as_mut()) {
+                            manifest.representations.push(finish_representation(rep, &base, &set_template));
+                        }
+                    }
+                    b"AdaptationSet" => {
+                        set_template = None;
+                        bases.pop();
+                    }
+                    b"Period" => {
+                        bases.pop();
+                    }
+                    _ => {}
+                }
+                buf.clear();
+                continue;
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {
+                buf.clear();
+                continue;
+            }
+            Err(e) => return Err(format!("broken manifest at {}: {}", reader.buffer_position(), e)),
+        };
+        buf.clear();
+        let attrs = attributes(&e)?;
+        let base = bases.last().cloned().unwrap_or_default();
+        match e.local_name() {
+            b"MPD" => {
+                let availability_start = attrs
+                    .get(&b"availabilityStartTime"[..])
+                    .and_then(|v| DateTime::parse_from_rfc3339(v.trim()).ok())
+                    .map(|t| t.timestamp());
+                manifest = Some(Manifest {
+                    live: attrs.get(&b"type"[..]).map(|t| t == "dynamic").unwrap_or(false),
+                    availability_start,
+                    representations: vec![],
+                });
+            }
+            b"BaseURL" => {
+                let mut text_buf = Vec::new();
+                let text = reader.read_text(e.name(), &mut text_buf).map_err(|e| e.to_string())?;
+                if let Some(last) = bases.last_mut() {
+                    *last = hls::join(&base, text.trim());
+                }
+            }
+            b"Period" if !empty => {
+                periods += 1;
+                if periods > 1 {
+                    break;
+                }
+                bases.push(base);
+            }
+            b"AdaptationSet" => {
+                set_attrs = attrs;
+                set_template = None;
+                if !empty {
+                    bases.push(base);
+                }
+            }
+            b"Representation" => {
+                let inherited = |name: &[u8]| {
+                    attrs
+                        .get(name)
+                        .or_else(|| set_attrs.get(name))
+                        .cloned()
+                        .unwrap_or_default()
+                };
+                let mut content_type = inherited(b"mimeType");
+                if content_type.is_empty() {
+                    content_type = inherited(b"contentType");
+                }
+                let rep = Representation {
+                    item: DashRepresentationItem {
+                        id: attrs.get(&b"id"[..]).cloned().unwrap_or_default(),
+                        content_type,
+                        codecs: inherited(b"codecs"),
+                        bandwidth: attribute_number(&attrs, b"bandwidth").unwrap_or(0),
+                    },
+                    base_url: String::from(""),
+                    template: None,
+                    segment_list: vec![],
+                };
+                if empty {
+                    if let Some(ref mut manifest) = manifest {
+                        manifest.representations.push(finish_representation(rep, &base, &set_template));
+                    }
+                } else {
+                    representation = Some(rep);
+                    bases.push(base);
+                }
+            }
+            b"SegmentTemplate" => {
+                let template = parse_template(&attrs);
+                match representation {
+                    Some(ref mut rep) => rep.template = Some(template),
+                    None => set_template = Some(template),
+                }
+            }
+            b"S" => {
+                let segment = (
+                    attribute_number(&attrs, b"t"),
+                    attribute_number(&attrs, b"d").unwrap_or(0),
+                    attrs.get(&b"r"[..]).and_then(|v| v.trim().parse().ok()).unwrap_or(0),
+                );
+                let template = match representation {
+                    Some(ref mut rep) => rep.template.as_mut(),
+                    None => set_template.as_mut(),
+                };
+                if let Some(template) = template {
+                    template.timeline.push(segment);
+                }
+            }
+            b"SegmentURL" => {
+                if let (Some(ref mut rep), Some(media)) = (representation.as_mut(), attrs.get(&b"media"[..])) {
+                    rep.segment_list.push(media.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut manifest = manifest.ok_or_else(|| String::from("not a dash manifest"))?;
+    if let Some(rep) = representation {
+        let base = bases.last().cloned().unwrap_or_default();
+        manifest.representations.push(finish_representation(rep, &base, &set_template));
+    }
+    Ok(manifest)
+}
+
+/// Representations inherit the template of their adaptation set,
+/// `base` includes the BaseURL of the representation itself.
+fn finish_representation(mut rep: Representation, base: &str, set_template: &Option<Template>) -> Representation {
+    if rep.template.is_none() {
+        rep.template = set_template.clone();
+    }
+    rep.base_url = base.to_string();
+    rep
+}
+
+/// Codec name as used for other streams, from an rfc 6381 codecs string
+pub fn codec_name(codecs: &str) -> String {
+    let codec = codecs.split(',').next().unwrap_or("").trim().to_lowercase();
+    match codec.as_str() {
+        "mp4a.40.5" | "mp4a.40.29" => String::from("AAC+"),
+        "mp4a.40.34" | "mp4a.6b" | "mp3" => String::from("MP3"),
+        "opus" => String::from("OPUS"),
+        "flac" => String::from("FLAC"),
+        "ac-3" => String::from("AC3"),
+        "ec-3" => String::from("EAC3"),
+        c if c.starts_with("mp4a.40") => String::from("AAC"),
+        c => c.to_uppercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn representation() -> DashRepresentationItem {
+        DashRepresentationItem {
+            id: String::from("audio_64k"),
+            content_type: String::from("audio/mp4"),
+            codecs: String::from("mp4a.40.2"),
+            bandwidth: 64000,
+        }
+    }
+
+    fn manifest(live: bool, availability_start: Option<i64>) -> Manifest {
+        Manifest {
+            live,
+            availability_start,
+            representations: vec![],
+        }
+    }
+
+    fn template(duration: u64, timeline: Vec<(Option<u64>, u64, i64)>) -> Template {
+        Template {
+            media: String::from("$RepresentationID$/$Number$.m4s"),
+            initialization: String::from(""),
+            start_number: 5,
+            timescale: 1000,
+            duration,
+            timeline,
+        }
+    }
+
+    #[test]
+    fn fill_template_identifiers() {
+        let rep = representation();
+        assert_eq!(
+            fill_template("$RepresentationID$/$Bandwidth$/seg-$Number$-$Time$.m4s", &rep, 7, 12000),
+            "audio_64k/64000/seg-7-12000.m4s"
+        );
+        assert_eq!(fill_template("seg$Number%05d$.m4s", &rep, 42, 0), "seg00042.m4s");
+        assert_eq!(fill_template("cost$$$Number$", &rep, 3, 0), "cost$3");
+        assert_eq!(fill_template("init.mp4", &rep, 1, 0), "init.mp4");
+        assert_eq!(fill_template("$Unknown$.m4s", &rep, 1, 0), "$Unknown$.m4s");
+    }
+
+    #[test]
+    fn timeline_static_uses_first_segment() {
+        let template = template(0, vec![(Some(1000), 2000, 3), (None, 1500, 0)]);
+        assert_eq!(segment_position(&manifest(false, None), &template), (5, 1000));
+    }
+
+    #[test]
+    fn timeline_live_uses_last_segment() {
+        // segments at 1000, 3000, 5000, 7000, then 9000 and 20000
+        let template = template(0, vec![(Some(1000), 2000, 3), (None, 1500, 0), (Some(20000), 1000, -1)]);
+        assert_eq!(segment_position(&manifest(true, None), &template), (10, 20000));
+    }
+
+    #[test]
+    fn timeline_with_huge_repeat() {
+        let repeats = 1_000_000_000_000;
+        let template = template(0, vec![(Some(0), 2, repeats)]);
+        let last = repeats as u64;
+        assert_eq!(segment_position(&manifest(true, None), &template), (5 + last, 2 * last));
+    }
+
+    #[test]
+    fn duration_template() {
+        let template = template(2000, vec![]);
+        assert_eq!(segment_position(&manifest(false, Some(0)), &template), (5, 0));
+        assert_eq!(segment_position(&manifest(true, None), &template), (5, 0));
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64 - 100;
+        let (number, time) = segment_position(&manifest(true, Some(start)), &template);
+        // 50 segments of 2s are available, two are skipped at the live edge
+        assert!((53..=54).contains(&number), "number {}", number);
+        assert_eq!(time, (number - 5) * 2000);
+    }
+}
@@ -1,199 +1,1052 @@
+use std::collections::HashSet;
 use std::error::Error;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
 use mysql;
+use mysql::Value;
+use mysql::prelude::FromValue;
+use uuid::Uuid;
 use models::StationItem;
 use models::StationCheckItemNew;
+use models::StationFaviconCheckItem;
+use models::FaviconHistoryItem;
+use models::FaviconRevert;
+use models::HomepageReportItem;
+use models::StationHomepageCheckItem;
+use models::SuspiciousReportItem;
+use models::TlsItem;
+use models::HlsItem;
+use models::DashItem;
+use models::SoakReportItem;
+use models::StationSoakCheckItem;
+use models::FingerprintItem;
+use models::FingerprintReportItem;
+use models::HttpsUpgradeReportItem;
+use models::TlsReportItem;
 
-pub fn get_stations_to_check(pool: &mysql::Pool, hours: u32, itemcount: u32) -> Vec<StationItem> {
-    let query = format!("SELECT StationID,StationUuid,Name,Codec,Bitrate,Hls,LastCheckOk,UrlCache,Url,Favicon,Homepage FROM Station WHERE LastCheckTime IS NULL OR LastCheckTime < NOW() - INTERVAL {} HOUR ORDER BY RAND() LIMIT {}", hours, itemcount);
-    get_stations_query(pool, query)
+/// Errors of the storage layer.
+#[derive(Debug)]
+pub enum DbError {
+    /// The database could not be reached or the connection broke down.
+    Connection(mysql::Error),
+    /// The database refused to execute a statement.
+    Query(mysql::Error),
+    /// A result row could not be decoded.
+    Decode(String),
 }
 
-fn get_stations_query(pool: &mysql::Pool, query: String) -> Vec<StationItem> {
-    let mut stations: Vec<StationItem> = vec![];
-    let results = pool.prep_exec(query, ());
-    for result in results {
-        for row_ in result {
-            let mut row = row_.unwrap();
-            let hls: i32 = row.take_opt("Hls").unwrap_or(Ok(0)).unwrap_or(0);
-            let ok: i32 = row.take_opt("LastCheckOk").unwrap_or(Ok(0)).unwrap_or(0);
-            let s = StationItem {
-                id:              row.take("StationID").unwrap(),
-                uuid:            row.take("StationUuid").unwrap_or("".to_string()),
-                name:            row.take_opt("Name").unwrap_or(Ok("".to_string())).unwrap_or("".to_string()),
-                url:             row.take_opt("Url").unwrap_or(Ok("".to_string())).unwrap_or("".to_string()),
-                urlcache:        row.take_opt("UrlCache").unwrap_or(Ok("".to_string())).unwrap_or("".to_string()),
-                codec:           row.take_opt("Codec").unwrap_or(Ok("".to_string())).unwrap_or("".to_string()),
-                bitrate:         row.take_opt("Bitrate").unwrap_or(Ok(0)).unwrap_or(0),
-                hls:             hls != 0,
-                check_ok:        ok != 0,
-                favicon:         row.take_opt("Favicon").unwrap_or(Ok("".to_string())).unwrap_or("".to_string()),
-                homepage:        row.take_opt("Homepage").unwrap_or(Ok("".to_string())).unwrap_or("".to_string()),
-            };
-            stations.push(s);
+impl DbError {
+    /// Errors that may go away if the operation is simply tried again,
+    /// like connection loss, deadlocks or lock wait timeouts.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            DbError::Connection(_) => true,
+            DbError::Query(mysql::Error::MySqlError(ref err)) => match err.code {
+                // ER_CON_COUNT_ERROR, ER_LOCK_WAIT_TIMEOUT, ER_LOCK_DEADLOCK
+                1040 | 1205 | 1213 => true,
+                // CR_SERVER_GONE_ERROR, CR_SERVER_LOST
+                2006 | 2013 => true,
+                _ => false,
+            },
+            DbError::Query(_) => false,
+            DbError::Decode(_) => false,
         }
     }
+}
 
-    stations
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DbError::Connection(ref err) => write!(f, "database connection error: {}", err),
+            DbError::Query(ref err) => write!(f, "database query error: {}", err),
+            DbError::Decode(ref msg) => write!(f, "database decode error: {}", msg),
+        }
+    }
 }
 
-pub fn get_station_count_broken(pool: &mysql::Pool) -> u32 {
-    let query = format!("SELECT COUNT(*) AS Items FROM radio.Station WHERE LastCheckOK=0 OR LastCheckOK IS NULL");
-    let results = pool.prep_exec(query, ());
-    for result in results {
-        for row_ in result {
-            let mut row = row_.unwrap();
-            let items: u32 = row.take_opt("Items").unwrap_or(Ok(0)).unwrap_or(0);
-            return items;
+impl Error for DbError {}
+
+impl From<mysql::Error> for DbError {
+    fn from(err: mysql::Error) -> DbError {
+        match err {
+            mysql::Error::FromValueError(value) => DbError::Decode(format!("unexpected value {:?}", value)),
+            mysql::Error::FromRowError(row) => DbError::Decode(format!("unexpected row {:?}", row)),
+            err => {
+                if err.is_connectivity_error() {
+                    DbError::Connection(err)
+                } else {
+                    DbError::Query(err)
+                }
+            }
         }
     }
-    return 0;
 }
 
-pub fn get_station_count_working(pool: &mysql::Pool) -> u32 {
-    let query = format!("SELECT COUNT(*) AS Items FROM radio.Station WHERE LastCheckOK=1");
-    let results = pool.prep_exec(query, ());
-    for result in results {
-        for row_ in result {
-            let mut row = row_.unwrap();
-            let items: u32 = row.take_opt("Items").unwrap_or(Ok(0)).unwrap_or(0);
-            return items;
+/// Default number of attempts for `retry`.
+pub const RETRY_ATTEMPTS: u32 = 5;
+
+/// Call `f` until it succeeds. Transient errors are retried up to
+/// `attempts` times with exponential backoff, permanent errors are
+/// returned immediately.
+pub fn retry<T, F>(name: &str, attempts: u32, f: F) -> Result<T, DbError>
+where
+    F: FnMut() -> Result<T, DbError>,
+{
+    retry_backoff(name, attempts, Duration::from_secs(1), f)
+}
+
+fn retry_backoff<T, F>(name: &str, attempts: u32, mut backoff: Duration, mut f: F) -> Result<T, DbError>
+where
+    F: FnMut() -> Result<T, DbError>,
+{
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                if !err.is_transient() || attempt >= attempts {
+                    return Err(err);
+                }
+                warn!("{}: {} (attempt {}/{}), retry in {} secs", name, err, attempt, attempts, backoff.as_secs());
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
         }
     }
-    return 0;
 }
 
-pub fn get_station_count_todo(pool: &mysql::Pool, hours: i32) -> u32 {
+/// Take a column from a row, NULL is replaced by `default`.
+fn take_or<T: FromValue>(row: &mut mysql::Row, column: &str, default: T) -> Result<T, DbError> {
+    match row.take_opt::<Option<T>, _>(column) {
+        Some(Ok(Some(value))) => Ok(value),
+        Some(Ok(None)) => Ok(default),
+        Some(Err(err)) => Err(DbError::Decode(format!("column {}: {:?}", column, err))),
+        None => Err(DbError::Decode(format!("column {} missing", column))),
+    }
+}
+
+pub fn get_stations_to_check(pool: &mysql::Pool, hours: u32, itemcount: u32) -> Result<Vec<StationItem>, DbError> {
+    let query = format!("SELECT {} FROM Station WHERE LastCheckTime IS NULL OR LastCheckTime < NOW() - INTERVAL {} HOUR ORDER BY RAND() LIMIT {}", STATION_COLUMNS, hours, itemcount);
+    get_stations_query(pool, query, ())
+}
+
+pub fn get_stations_to_check_favicon(pool: &mysql::Pool, hours: u32, itemcount: u32) -> Result<Vec<StationItem>, DbError> {
+    let query = format!("SELECT {} FROM Station WHERE LastFaviconCheckTime IS NULL OR LastFaviconCheckTime < NOW() - INTERVAL {} HOUR ORDER BY RAND() LIMIT {}", STATION_COLUMNS, hours, itemcount);
+    get_stations_query(pool, query, ())
+}
+
+/// Stations with a homepage that was not checked by this source for `hours`
+pub fn get_stations_to_check_homepage(pool: &mysql::Pool, hours: u32, itemcount: u32, source: &str) -> Result<Vec<StationItem>, DbError> {
+    let query = format!("SELECT {} FROM Station WHERE Homepage<>'' AND StationUuid NOT IN (SELECT StationUuid FROM StationHomepageCheck WHERE Source=? AND CheckTime >= NOW() - INTERVAL {} HOUR) ORDER BY RAND() LIMIT {}", STATION_COLUMNS, hours, itemcount);
+    get_stations_query(pool, query, (source,))
+}
+
+/// Working stations without a soak check for `hours`, hls streams are
+/// not soaked.
+pub fn get_stations_to_check_soak(pool: &mysql::Pool, hours: u32, itemcount: u32, source: &str) -> Result<Vec<StationItem>, DbError> {
+    let query = format!("SELECT {} FROM Station WHERE LastCheckOk=1 AND Hls=0 AND StationUuid NOT IN (SELECT StationUuid FROM StationSoakCheck WHERE Source=? AND CheckTime >= NOW() - INTERVAL {} HOUR) ORDER BY RAND() LIMIT {}", STATION_COLUMNS, hours, itemcount);
+    get_stations_query(pool, query, (source,))
+}
+
+const STATION_COLUMNS: &str = "StationID,StationUuid,Name,Codec,Bitrate,Hls,LastCheckOk,UrlCache,Url,Favicon,FaviconMirror,Homepage";
+
+fn get_stations_query<P: Into<mysql::Params>>(pool: &mysql::Pool, query: String, params: P) -> Result<Vec<StationItem>, DbError> {
+    let mut stations: Vec<StationItem> = vec![];
+    let results = pool.prep_exec(query, params)?;
+    for row_ in results {
+        let mut row = row_?;
+        let hls: i32 = take_or(&mut row, "Hls", 0)?;
+        let ok: i32 = take_or(&mut row, "LastCheckOk", 0)?;
+        let s = StationItem {
+            id:              take_or(&mut row, "StationID", 0)?,
+            uuid:            take_or(&mut row, "StationUuid", "".to_string())?,
+            name:            take_or(&mut row, "Name", "".to_string())?,
+            url:             take_or(&mut row, "Url", "".to_string())?,
+            urlcache:        take_or(&mut row, "UrlCache", "".to_string())?,
+            codec:           take_or(&mut row, "Codec", "".to_string())?,
+            bitrate:         take_or(&mut row, "Bitrate", 0)?,
+            hls:             hls != 0,
+            check_ok:        ok != 0,
+            favicon:         take_or(&mut row, "Favicon", "".to_string())?,
+            favicon_mirror:  take_or(&mut row, "FaviconMirror", "".to_string())?,
+            homepage:        take_or(&mut row, "Homepage", "".to_string())?,
+        };
+        stations.push(s);
+    }
+
+    Ok(stations)
+}
+
+fn get_count<P: Into<mysql::Params>>(pool: &mysql::Pool, query: &str, params: P) -> Result<u32, DbError> {
+    let row = pool.first_exec(query, params)?;
+    match row {
+        Some(mut row) => take_or(&mut row, "Items", 0),
+        None => Err(DbError::Decode(format!("no result row for '{}'", query))),
+    }
+}
+
+fn execute<P: Into<mysql::Params>>(pool: &mysql::Pool, query: &str, params: P) -> Result<u64, DbError> {
+    let result = pool.prep_exec(query, params)?;
+    Ok(result.affected_rows())
+}
+
+pub fn get_station_count_broken(pool: &mysql::Pool) -> Result<u32, DbError> {
+    get_count(pool, "SELECT COUNT(*) AS Items FROM radio.Station WHERE LastCheckOK=0 OR LastCheckOK IS NULL", ())
+}
+
+pub fn get_station_count_suspicious(pool: &mysql::Pool) -> Result<u32, DbError> {
+    get_count(pool, "SELECT COUNT(*) AS Items FROM radio.Station WHERE Suspicious IS NOT NULL", ())
+}
+
+pub fn get_station_count_working(pool: &mysql::Pool) -> Result<u32, DbError> {
+    get_count(pool, "SELECT COUNT(*) AS Items FROM radio.Station WHERE LastCheckOK=1", ())
+}
+
+pub fn get_station_count_todo(pool: &mysql::Pool, hours: i32) -> Result<u32, DbError> {
     let query = format!("SELECT COUNT(*) AS Items FROM Station WHERE LastCheckTime IS NULL OR LastCheckTime < NOW() - INTERVAL {} HOUR", hours);
-    let results = pool.prep_exec(query, ());
-    for result in results {
-        for row_ in result {
-            let mut row = row_.unwrap();
-            let items: u32 = row.take_opt("Items").unwrap_or(Ok(0)).unwrap_or(0);
-            return items;
+    get_count(pool, &query, ())
+}
+
+pub fn get_checks(pool: &mysql::Pool, hours: u32, source: &str) -> Result<u32, DbError> {
+    let query = format!("SELECT COUNT(*) AS Items FROM StationCheckHistory WHERE Source=? AND CheckTime > NOW() - INTERVAL {} HOUR", hours);
+    get_count(pool, &query, (source,))
+}
+
+pub fn get_deletable_never_working(pool: &mysql::Pool, hours: u32) -> Result<u32, DbError> {
+    let query = format!("SELECT COUNT(*) AS Items FROM Station WHERE LastCheckOkTime IS NULL AND Creation < NOW() - INTERVAL {} HOUR AND COALESCE(GeoRestricted,0)=0", hours);
+    get_count(pool, &query, ())
+}
+
+pub fn get_deletable_were_working(pool: &mysql::Pool, hours: u32) -> Result<u32, DbError> {
+    let query = format!("SELECT COUNT(*) AS Items FROM Station WHERE LastCheckOk=0 AND LastCheckOkTime IS NOT NULL AND LastCheckOkTime < NOW() - INTERVAL {} HOUR AND COALESCE(GeoRestricted,0)=0", hours);
+    get_count(pool, &query, ())
+}
+
+pub fn delete_never_working(pool: &mysql::Pool, hours: u32) -> Result<u64, DbError> {
+    let query = format!("DELETE FROM Station WHERE LastCheckOkTime IS NULL AND Creation < NOW() - INTERVAL {} HOUR AND COALESCE(GeoRestricted,0)=0", hours);
+    execute(pool, &query, ())
+}
+
+pub fn delete_were_working(pool: &mysql::Pool, hours: u32) -> Result<u64, DbError> {
+    let query = format!("DELETE FROM Station WHERE LastCheckOk=0 AND LastCheckOkTime IS NOT NULL AND LastCheckOkTime < NOW() - INTERVAL {} HOUR AND COALESCE(GeoRestricted,0)=0", hours);
+    execute(pool, &query, ())
+}
+
+/// Record a batch of check results: replace the current checks of the
+/// sources, append them to the history, update the stations and store the
+/// details of each feature, all in one transaction. Current and history
+/// row of a result share the same check uuid.
+pub fn save_checks(pool: &mysql::Pool, items: &[StationCheckItemNew]) -> Result<(), DbError> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let check_uuids: Vec<String> = items.iter().map(|_| Uuid::new_v4().to_hyphenated().to_string()).collect();
+
+    let mut transaction = pool.start_transaction(false, None, None)?;
+    save_check_rows(&mut transaction, items, &check_uuids)?;
+    save_station_state(&mut transaction, items)?;
+    save_stream_tls(&mut transaction, items)?;
+    save_https_upgrades(&mut transaction, items)?;
+    save_hls_checks(&mut transaction, items, &check_uuids)?;
+    save_dash_checks(&mut transaction, items, &check_uuids)?;
+    save_region_checks(&mut transaction, items, &check_uuids)?;
+    save_now_playing(&mut transaction, items)?;
+    save_server_status(&mut transaction, items)?;
+    save_fingerprints(&mut transaction, items)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Replace the current checks and append to the history
+fn save_check_rows(transaction: &mut mysql::Transaction, items: &[StationCheckItemNew], check_uuids: &[String]) -> Result<(), DbError> {
+    // only the last result of a station and source may become the current check
+    let mut current: Vec<usize> = vec![];
+    for (index, item) in items.iter().enumerate() {
+        current.retain(|&i| items[i].station_uuid != item.station_uuid || items[i].source != item.source);
+        current.push(index);
+    }
+
+    let mut params: Vec<Value> = vec![];
+    for &i in current.iter() {
+        params.push(items[i].station_uuid.clone().into());
+        params.push(items[i].source.clone().into());
+    }
+    let query = format!("DELETE FROM StationCheck WHERE (StationUuid,Source) IN ({})", placeholders(current.len(), "(?,?)"));
+    transaction.prep_exec(query, params)?;
+
+    let mut params: Vec<Value> = vec![];
+    for &i in current.iter() {
+        params.extend(check_values(&items[i], &check_uuids[i]));
+    }
+    let query = format!("INSERT INTO StationCheck(StationUuid,CheckUuid,Source,Codec,Bitrate,Hls,CheckOK,CheckTime,UrlCache,LoudnessRms,LoudnessPeak,SilenceSeconds,Silent,BitrateMeasured,BitrateMismatch,Dash) VALUES{}", placeholders(current.len(), "(?,?,?,?,?,?,?,NOW(),?,?,?,?,?,?,?,?)"));
+    transaction.prep_exec(query, params)?;
+
+    let mut params: Vec<Value> = vec![];
+    for (item, check_uuid) in items.iter().zip(check_uuids.iter()) {
+        params.extend(check_values(item, check_uuid));
+    }
+    let query = format!("INSERT INTO StationCheckHistory(StationUuid,CheckUuid,Source,Codec,Bitrate,Hls,CheckOK,CheckTime,UrlCache,LoudnessRms,LoudnessPeak,SilenceSeconds,Silent,BitrateMeasured,BitrateMismatch,Dash) VALUES{}", placeholders(items.len(), "(?,?,?,?,?,?,?,NOW(),?,?,?,?,?,?,?,?)"));
+    transaction.prep_exec(query, params)?;
+    Ok(())
+}
+
+/// Update check state, codec, bitrate and url of the stations, the last
+/// result of a station decides its state
+fn save_station_state(transaction: &mut mysql::Transaction, items: &[StationCheckItemNew]) -> Result<(), DbError> {
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut suspicious: Vec<(&String, Vec<Option<Value>>)> = vec![];
+    let mut working: Vec<(&String, Vec<Option<Value>>)> = vec![];
+    let mut broken: Vec<(&String, Vec<Option<Value>>)> = vec![];
+    for item in items.iter().rev().filter(|item| seen.insert(&item.station_uuid)) {
+        let geo_restricted = item.geo_restricted.map(Value::from);
+        if let Some(ref reason) = item.suspicious {
+            // suspicious stations keep their state, codec, bitrate and url
+            // until an operator clears the mark, so they are compared with
+            // the last accepted url and not deleted as broken
+            suspicious.push((&item.station_uuid, vec![Some(reason.into()), geo_restricted]));
+        } else if item.check_ok {
+            working.push((&item.station_uuid, vec![Some(item.codec.clone().into()), Some(item.bitrate.into()), Some(item.url.clone().into()), geo_restricted]));
+        } else if item.geo_restricted == Some(true) {
+            // geo-restricted streams work, only not from here: the local
+            // result is stored, but codec, bitrate and url are kept and the
+            // mark keeps the station from being deleted as broken
+            broken.push((&item.station_uuid, vec![None, None, None, geo_restricted]));
+        } else {
+            broken.push((&item.station_uuid, vec![Some(item.codec.clone().into()), Some(item.bitrate.into()), Some(item.url.clone().into()), geo_restricted]));
         }
     }
-    return 0;
+    update_stations(transaction, "LastCheckTime=NOW()", &["Suspicious", "GeoRestricted"], &suspicious)?;
+    update_stations(transaction, "LastCheckTime=NOW(),LastCheckOkTime=NOW(),LastCheckOk=1", &["Codec", "Bitrate", "UrlCache", "GeoRestricted"], &working)?;
+    update_stations(transaction, "LastCheckTime=NOW(),LastCheckOk=0", &["Codec", "Bitrate", "UrlCache", "GeoRestricted"], &broken)?;
+    Ok(())
 }
 
-pub fn get_checks(pool: &mysql::Pool, hours: u32, source: &str) -> u32 {
-    let query = format!("SELECT COUNT(*) AS Items FROM StationCheckHistory WHERE Source=? AND CheckTime > NOW() - INTERVAL {} HOUR", hours);
-    let results = pool.prep_exec(query, (source,));
-    for result in results {
-        for row_ in result {
-            let mut row = row_.unwrap();
-            let items: u32 = row.take_opt("Items").unwrap_or(Ok(0)).unwrap_or(0);
-            return items;
+/// One statement for many stations: `fixed` is set the same way on all of
+/// them, `columns` get the value of each station, `None` keeps the column.
+fn update_stations(transaction: &mut mysql::Transaction, fixed: &str, columns: &[&str], rows: &[(&String, Vec<Option<Value>>)]) -> Result<(), DbError> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let (query, params) = update_stations_query(fixed, columns, rows);
+    transaction.prep_exec(query, params)?;
+    Ok(())
+}
+
+fn update_stations_query(fixed: &str, columns: &[&str], rows: &[(&String, Vec<Option<Value>>)]) -> (String, Vec<Value>) {
+    let mut set = vec![String::from(fixed)];
+    let mut params: Vec<Value> = vec![];
+    for (index, column) in columns.iter().enumerate() {
+        let mut cases = String::new();
+        for &(uuid, ref values) in rows.iter() {
+            if let Some(ref value) = values[index] {
+                cases.push_str(" WHEN ? THEN ?");
+                params.push(uuid.clone().into());
+                params.push(value.clone());
+            }
+        }
+        if !cases.is_empty() {
+            set.push(format!("{}=CASE StationUuid{} ELSE {} END", column, cases, column));
         }
     }
-    return 0;
+    params.extend(rows.iter().map(|&(uuid, _)| Value::from(uuid.clone())));
+    let query = format!("UPDATE Station SET {} WHERE StationUuid IN ({})", set.join(","), placeholders(rows.len(), "?"));
+    (query, params)
+}
+
+fn save_stream_tls(transaction: &mut mysql::Transaction, items: &[StationCheckItemNew]) -> Result<(), DbError> {
+    let tls_items: Vec<(&String, &String, &TlsItem)> = items
+        .iter()
+        .filter_map(|item| item.tls.as_ref().map(|tls| (&item.station_uuid, &item.source, tls)))
+        .collect();
+    if !tls_items.is_empty() {
+        transaction.prep_exec(tls_query(tls_items.len()), tls_values("stream", &tls_items))?;
+    }
+    Ok(())
 }
 
-pub fn get_deletable_never_working(pool: &mysql::Pool, hours: u32) -> u32 {
-    let query = format!("SELECT COUNT(*) AS Items FROM Station WHERE LastCheckOkTime IS NULL AND Creation < NOW() - INTERVAL {} HOUR", hours);
-    let results = pool.prep_exec(query, ());
-    for result in results {
-        for row_ in result {
-            let mut row = row_.unwrap();
-            let items: u32 = row.take_opt("Items").unwrap_or(Ok(0)).unwrap_or(0);
-            return items;
+fn save_https_upgrades(transaction: &mut mysql::Transaction, items: &[StationCheckItemNew]) -> Result<(), DbError> {
+    for item in items.iter() {
+        if let Some(ref upgrade) = item.https_upgrade {
+            let query = "REPLACE INTO StationHttpsUpgrade(StationUuid,Source,OldUrl,NewUrl,Applied,CheckTime) VALUES(?,?,?,?,?,NOW())";
+            transaction.prep_exec(query, (&item.station_uuid,&item.source,&upgrade.old_url,&upgrade.new_url,&upgrade.applied))?;
+            if upgrade.applied {
+                // only replace the url if nobody changed it meanwhile
+                let query = "UPDATE Station SET Url=? WHERE StationUuid=? AND Url=?";
+                transaction.prep_exec(query, (&upgrade.new_url,&item.station_uuid,&upgrade.old_url))?;
+            }
         }
     }
-    return 0;
+    Ok(())
+}
+
+fn save_fingerprints(transaction: &mut mysql::Transaction, items: &[StationCheckItemNew]) -> Result<(), DbError> {
+    let fingerprints: Vec<(&StationCheckItemNew, &FingerprintItem)> = items
+        .iter()
+        .filter_map(|item| item.fingerprint.as_ref().map(|fingerprint| (item, fingerprint)))
+        .collect();
+    if fingerprints.is_empty() {
+        return Ok(());
+    }
+    let mut params: Vec<Value> = vec![];
+    for &(item, fingerprint) in fingerprints.iter() {
+        params.push(item.station_uuid.clone().into());
+        params.push(item.source.clone().into());
+        params.push(fingerprint.capture_millis.into());
+        params.push(fingerprint.fingerprint.clone().into());
+    }
+    let query = format!("INSERT INTO StationFingerprint(StationUuid,Source,CaptureMillis,Fingerprint) VALUES{}", placeholders(fingerprints.len(), "(?,?,?,?)"));
+    transaction.prep_exec(query, params)?;
+    Ok(())
 }
 
-pub fn get_deletable_were_working(pool: &mysql::Pool, hours: u32) -> u32 {
-    let query = format!("SELECT COUNT(*) AS Items FROM Station WHERE LastCheckOk=0 AND LastCheckOkTime IS NOT NULL AND LastCheckOkTime < NOW() - INTERVAL {} HOUR", hours);
-    let results = pool.prep_exec(query, ());
-    for result in results {
-        for row_ in result {
-            let mut row = row_.unwrap();
-            let items: u32 = row.take_opt("Items").unwrap_or(Ok(0)).unwrap_or(0);
-            return items;
+fn save_hls_checks(transaction: &mut mysql::Transaction, items: &[StationCheckItemNew], check_uuids: &[String]) -> Result<(), DbError> {
+    let hls_items: Vec<(&StationCheckItemNew, &String, &HlsItem)> = items
+        .iter()
+        .zip(check_uuids.iter())
+        .filter_map(|(item, check_uuid)| item.hls_details.as_ref().map(|hls| (item, check_uuid, hls)))
+        .collect();
+    if hls_items.is_empty() {
+        return Ok(());
+    }
+    let mut params: Vec<Value> = vec![];
+    for &(item, check_uuid, hls) in hls_items.iter() {
+        params.push(check_uuid.clone().into());
+        params.push(item.station_uuid.clone().into());
+        params.push(item.source.clone().into());
+        params.push(hls.status.clone().into());
+        params.push(hls.media_playlist.clone().into());
+        params.push(hls.target_duration.into());
+        params.push(hls.media_sequence.into());
+        params.push(hls.segments.into());
+        params.push(hls.live.into());
+        params.push(hls.edge_moved.into());
+        params.push(hls.segment_http_status.into());
+        params.push(hls.error.clone().into());
+    }
+    let query = format!("INSERT INTO StationHlsCheck(CheckUuid,StationUuid,Source,Status,MediaPlaylist,TargetDuration,MediaSequence,Segments,Live,EdgeMoved,SegmentHttpStatus,Error,CheckTime) VALUES{}", placeholders(hls_items.len(), "(?,?,?,?,?,?,?,?,?,?,?,?,NOW())"));
+    transaction.prep_exec(query, params)?;
+
+    let mut params: Vec<Value> = vec![];
+    let mut count = 0;
+    for &(_, check_uuid, hls) in hls_items.iter() {
+        for variant in hls.variants.iter() {
+            params.push(check_uuid.clone().into());
+            params.push(variant.bandwidth.into());
+            params.push(variant.average_bandwidth.into());
+            params.push(variant.codecs.clone().into());
+            params.push(variant.resolution.clone().into());
+            params.push(variant.uri.clone().into());
+            count += 1;
         }
     }
-    return 0;
+    if count > 0 {
+        let query = format!("INSERT INTO StationHlsVariant(CheckUuid,Bandwidth,AverageBandwidth,Codecs,Resolution,Uri) VALUES{}", placeholders(count, "(?,?,?,?,?,?)"));
+        transaction.prep_exec(query, params)?;
+    }
+    Ok(())
 }
 
-pub fn delete_never_working(pool: &mysql::Pool, hours: u32) {
-    let query = format!("DELETE FROM Station WHERE LastCheckOkTime IS NULL AND Creation < NOW() - INTERVAL {} HOUR", hours);
-    let mut my_stmt = pool.prepare(query).unwrap();
-    let result = my_stmt.execute(());
-    match result {
-        Ok(_) => {},
-        Err(err) => {println!("{}",err);}
+fn save_dash_checks(transaction: &mut mysql::Transaction, items: &[StationCheckItemNew], check_uuids: &[String]) -> Result<(), DbError> {
+    let dash_items: Vec<(&StationCheckItemNew, &String, &DashItem)> = items
+        .iter()
+        .zip(check_uuids.iter())
+        .filter_map(|(item, check_uuid)| item.dash_details.as_ref().map(|dash| (item, check_uuid, dash)))
+        .collect();
+    if dash_items.is_empty() {
+        return Ok(());
+    }
+    let mut params: Vec<Value> = vec![];
+    for &(item, check_uuid, dash) in dash_items.iter() {
+        params.push(check_uuid.clone().into());
+        params.push(item.station_uuid.clone().into());
+        params.push(item.source.clone().into());
+        params.push(dash.status.clone().into());
+        params.push(dash.live.into());
+        params.push(dash.segment_url.clone().into());
+        params.push(dash.segment_http_status.into());
+        params.push(dash.error.clone().into());
+    }
+    let query = format!("INSERT INTO StationDashCheck(CheckUuid,StationUuid,Source,Status,Live,SegmentUrl,SegmentHttpStatus,Error,CheckTime) VALUES{}", placeholders(dash_items.len(), "(?,?,?,?,?,?,?,?,NOW())"));
+    transaction.prep_exec(query, params)?;
+
+    let mut params: Vec<Value> = vec![];
+    let mut count = 0;
+    for &(_, check_uuid, dash) in dash_items.iter() {
+        for representation in dash.representations.iter() {
+            params.push(check_uuid.clone().into());
+            params.push(representation.id.clone().into());
+            params.push(representation.content_type.clone().into());
+            params.push(representation.codecs.clone().into());
+            params.push(representation.bandwidth.into());
+            count += 1;
+        }
     }
+    if count > 0 {
+        let query = format!("INSERT INTO StationDashRepresentation(CheckUuid,RepresentationId,ContentType,Codecs,Bandwidth) VALUES{}", placeholders(count, "(?,?,?,?,?)"));
+        transaction.prep_exec(query, params)?;
+    }
+    Ok(())
 }
 
-pub fn delete_were_working(pool: &mysql::Pool, hours: u32) {
-    let query = format!("DELETE FROM Station WHERE LastCheckOk=0 AND LastCheckOkTime IS NOT NULL AND LastCheckOkTime < NOW() - INTERVAL {} HOUR", hours);
-    let mut my_stmt = pool.prepare(query).unwrap();
-    let result = my_stmt.execute(());
-    match result {
-        Ok(_) => {},
-        Err(err) => {println!("{}",err);}
+fn save_now_playing(transaction: &mut mysql::Transaction, items: &[StationCheckItemNew]) -> Result<(), DbError> {
+    // keep the last title if none was seen this time
+    let query = "INSERT INTO StationNowPlaying(StationUuid,Source,MetadataType,HasMetadata,Title,TitleTime,CheckTime) VALUES(?,?,?,?,?,IF(?,NOW(),NULL),NOW()) ON DUPLICATE KEY UPDATE MetadataType=VALUES(MetadataType),HasMetadata=VALUES(HasMetadata),Title=IF(VALUES(HasMetadata),VALUES(Title),Title),TitleTime=IF(VALUES(HasMetadata),NOW(),TitleTime),CheckTime=NOW()";
+    for item in items.iter() {
+        if let Some(ref now_playing) = item.now_playing {
+            let title: String = now_playing.title.chars().take(512).collect();
+            transaction.prep_exec(query, (&item.station_uuid,&item.source,&now_playing.metadata_type,&now_playing.has_metadata,&title,&now_playing.has_metadata))?;
+        }
     }
+    Ok(())
 }
 
-pub fn insert_check(pool: &mysql::Pool,item: &StationCheckItemNew) -> Result<(), Box<std::error::Error>> {
-    let query = "DELETE FROM StationCheck WHERE StationUuid=:stationuuid AND Source=:source";
-    let mut my_stmt = pool.prepare(query)?;
-    my_stmt.execute(params!(
-        "stationuuid" => &item.station_uuid,
-        "source" => &item.source
-    ))?;
+fn save_server_status(transaction: &mut mysql::Transaction, items: &[StationCheckItemNew]) -> Result<(), DbError> {
+    let query_status = "INSERT INTO StationServerStatus(StationUuid,Source,ServerType,Version,StatusUrl,Listeners,PeakListeners,Description,Mount,CheckTime) VALUES(?,?,?,?,?,?,?,?,?,NOW()) ON DUPLICATE KEY UPDATE ServerType=VALUES(ServerType),Version=VALUES(Version),StatusUrl=VALUES(StatusUrl),Listeners=VALUES(Listeners),PeakListeners=VALUES(PeakListeners),Description=VALUES(Description),Mount=VALUES(Mount),CheckTime=NOW()";
+    // sibling mounts are replaced with the ones listed this time
+    let query_delete_mounts = "DELETE FROM StationServerMount WHERE StationUuid=? AND Source=?";
+    let query_mount = "INSERT INTO StationServerMount(StationUuid,Source,Mount,Url,ContentType,Bitrate,Listeners) VALUES(?,?,?,?,?,?,?)";
+    for item in items.iter() {
+        if let Some(ref status) = item.server_status {
+            let version: String = status.version.chars().take(100).collect();
+            let description: String = status.description.chars().take(512).collect();
+            let mount: String = status.mount.chars().take(255).collect();
+            transaction.prep_exec(query_status, (&item.station_uuid,&item.source,&status.server_type,&version,&status.status_url,&status.listeners,&status.peak_listeners,&description,&mount))?;
+            transaction.prep_exec(query_delete_mounts, (&item.station_uuid,&item.source))?;
+            for sibling in status.siblings.iter() {
+                let mount: String = sibling.mount.chars().take(255).collect();
+                let content_type: String = sibling.content_type.chars().take(100).collect();
+                transaction.prep_exec(query_mount, (&item.station_uuid,&item.source,&mount,&sibling.url,&content_type,&sibling.bitrate,&sibling.listeners))?;
+            }
+        }
+    }
+    Ok(())
+}
 
-    let query2 = "INSERT INTO StationCheck(StationUuid,CheckUuid,Source,Codec,Bitrate,Hls,CheckOK,CheckTime,UrlCache) VALUES(?,UUID(),?,?,?,?,?,NOW(),?)";
-    let mut my_stmt2 = pool.prepare(query2)?;
-    my_stmt2.execute((&item.station_uuid,&item.source,&item.codec,&item.bitrate,&item.hls,&item.check_ok,&item.url))?;
+fn save_region_checks(transaction: &mut mysql::Transaction, items: &[StationCheckItemNew], check_uuids: &[String]) -> Result<(), DbError> {
+    let count: usize = items.iter().map(|item| item.regions.len()).sum();
+    if count == 0 {
+        return Ok(());
+    }
+    let mut params: Vec<Value> = vec![];
+    for (item, check_uuid) in items.iter().zip(check_uuids.iter()) {
+        for region in item.regions.iter() {
+            params.push(check_uuid.clone().into());
+            params.push(item.station_uuid.clone().into());
+            params.push(item.source.clone().into());
+            params.push(region.region.clone().into());
+            params.push(region.check_ok.into());
+            params.push(region.http_status.into());
+            params.push(region.error.clone().into());
+        }
+    }
+    let query = format!("INSERT INTO StationRegionCheck(CheckUuid,StationUuid,Source,Region,CheckOK,HttpStatus,Error,CheckTime) VALUES{}", placeholders(count, "(?,?,?,?,?,?,?,NOW())"));
+    transaction.prep_exec(query, params)?;
+    Ok(())
+}
+
+/// Record the results of favicon checks: mark the stations as checked,
+/// apply favicon changes with their history and update mirror paths.
+pub fn save_favicon_checks(pool: &mysql::Pool, items: &[StationFaviconCheckItem]) -> Result<(), DbError> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let mut transaction = pool.start_transaction(false, None, None)?;
+
+    let checked: Vec<(&String, Vec<Option<Value>>)> = items
+        .iter()
+        .map(|item| {
+            let size = item.favicon_size;
+            (&item.station_uuid, vec![size.map(|(width, _)| width.into()), size.map(|(_, height)| height.into())])
+        })
+        .collect();
+    update_stations(&mut transaction, "LastFaviconCheckTime=NOW()", &["FaviconWidth", "FaviconHeight"], &checked)?;
 
-    let query3 = "INSERT INTO StationCheckHistory(StationUuid,CheckUuid,Source,Codec,Bitrate,Hls,CheckOK,CheckTime,UrlCache) VALUES(?,UUID(),?,?,?,?,?,NOW(),?)";
-    let mut my_stmt3 = pool.prepare(query3)?;
-    my_stmt3.execute((&item.station_uuid,&item.source,&item.codec,&item.bitrate,&item.hls,&item.check_ok,&item.url))?;
+    let mut pinned: HashSet<&str> = HashSet::new();
+    for item in items.iter() {
+        if let Some(ref change) = item.favicon_change {
+            // pinned favicons were reverted by an operator and stay
+            let query2 = "UPDATE Station SET Favicon=?,FaviconWidth=?,FaviconHeight=? WHERE StationUuid=? AND FaviconPinned=0";
+            let updated = transaction
+                .prep_exec(query2, (&change.new.url,&change.new.width,&change.new.height,&item.station_uuid))?
+                .affected_rows();
+            if updated == 0 {
+                debug!("Favicon of {} is pinned, keep it", item.station_uuid);
+                pinned.insert(&item.station_uuid);
+                continue;
+            }
+            let query3 = "INSERT INTO StationFaviconHistory(StationUuid,Source,OldFavicon,NewFavicon,Width,Height,ChangeTime) VALUES(?,?,?,?,?,?,NOW())";
+            transaction.prep_exec(query3, (&item.station_uuid,&item.source,&change.old,&change.new.url,&change.new.width,&change.new.height))?;
+        }
+    }
+    {
+        let query4 = "UPDATE Station SET FaviconMirror=? WHERE StationUuid=?";
+        let mut stmt = transaction.prepare(query4)?;
+        for item in items.iter() {
+            if let Some(ref mirror) = item.favicon_mirror {
+                if pinned.contains(item.station_uuid.as_str()) {
+                    continue;
+                }
+                stmt.execute((mirror,&item.station_uuid))?;
+            }
+        }
+    }
+
+    transaction.commit()?;
     Ok(())
 }
 
-pub fn update_station(pool: &mysql::Pool,item: &StationCheckItemNew){
-    let mut query: String = String::from("UPDATE Station SET LastCheckTime=NOW(),LastCheckOkTime=NOW(),LastCheckOk=?,Codec=?,Bitrate=?,UrlCache=? WHERE StationUuid=?");
-    if !item.check_ok{
-        query = format!("UPDATE Station SET LastCheckTime=NOW(),LastCheckOk=?,Codec=?,Bitrate=?,UrlCache=? WHERE StationUuid=?");
+/// Replace the homepage check results of the stations
+pub fn save_homepage_checks(pool: &mysql::Pool, items: &[StationHomepageCheckItem]) -> Result<(), DbError> {
+    if items.is_empty() {
+        return Ok(());
     }
-    let mut my_stmt = pool.prepare(query).unwrap();
-    let result = my_stmt.execute((&item.check_ok,&item.codec,&item.bitrate,&item.url,&item.station_uuid));
-    match result {
-        Ok(_) => {},
-        Err(err) => {println!("{}",err);}
+    let mut params: Vec<Value> = vec![];
+    for item in items.iter() {
+        let homepage = &item.homepage;
+        params.push(item.station_uuid.clone().into());
+        params.push(item.source.clone().into());
+        params.push(homepage.status.clone().into());
+        params.push(homepage.http_status.into());
+        params.push(homepage.final_url.clone().into());
+        params.push(homepage.title.clone().into());
+        params.push(homepage.resolves.into());
+        params.push(homepage.tls_valid.map(Value::from).unwrap_or(Value::NULL));
+        params.push(homepage.parked.into());
     }
+    let query = format!("REPLACE INTO StationHomepageCheck(StationUuid,Source,Status,HttpStatus,FinalUrl,Title,Resolves,TlsValid,Parked,CheckTime) VALUES{}", placeholders(items.len(), "(?,?,?,?,?,?,?,?,?,NOW())"));
+    pool.prep_exec(query, params)?;
+
+    let tls_items: Vec<(&String, &String, &TlsItem)> = items
+        .iter()
+        .filter_map(|item| item.homepage.tls.as_ref().map(|tls| (&item.station_uuid, &item.source, tls)))
+        .collect();
+    if !tls_items.is_empty() {
+        pool.prep_exec(tls_query(tls_items.len()), tls_values("homepage", &tls_items))?;
+    }
+    Ok(())
 }
 
-pub fn delete_old_checks(pool: &mysql::Pool, hours: u32) {
-    let query = format!("DELETE FROM StationCheckHistory WHERE CheckTime < NOW() - INTERVAL {} HOUR", hours);
-    let mut my_stmt = pool.prepare(query).unwrap();
-    let result = my_stmt.execute(());
-    match result {
-        Ok(_) => {},
-        Err(err) => {println!("{}",err);}
+pub fn save_soak_checks(pool: &mysql::Pool, items: &[StationSoakCheckItem]) -> Result<(), DbError> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let mut params: Vec<Value> = vec![];
+    for item in items.iter() {
+        let soak = &item.soak;
+        params.push(item.station_uuid.clone().into());
+        params.push(item.source.clone().into());
+        params.push(soak.listen_secs.into());
+        params.push(soak.underruns.into());
+        params.push(soak.stalled_secs.into());
+        params.push(soak.disconnects.into());
+        params.push(soak.reconnects.into());
+        params.push(soak.failed_reconnects.into());
+        params.push(soak.score.into());
+        params.push(soak.error.clone().into());
+    }
+    let query = format!("REPLACE INTO StationSoakCheck(StationUuid,Source,ListenSeconds,Underruns,StalledSeconds,Disconnects,Reconnects,FailedReconnects,Score,Error,CheckTime) VALUES{}", placeholders(items.len(), "(?,?,?,?,?,?,?,?,?,?,NOW())"));
+    pool.prep_exec(query, params)?;
+    Ok(())
+}
+
+/// Latest soak check results of all sources with a score below `max_score`
+pub fn get_soak_report(pool: &mysql::Pool, max_score: u32) -> Result<Vec<SoakReportItem>, DbError> {
+    let query = "SELECT k.StationUuid,s.Name,s.Url,k.Score,k.Underruns,k.Disconnects,k.FailedReconnects,DATE_FORMAT(k.CheckTime,'%Y-%m-%d %H:%i:%s') AS CheckTime FROM StationSoakCheck k JOIN Station s ON s.StationUuid=k.StationUuid WHERE k.Score<? ORDER BY k.Score,s.Name";
+    let results = pool.prep_exec(query, (max_score,))?;
+    let mut list = vec![];
+    for row_ in results {
+        let mut row = row_?;
+        list.push(SoakReportItem {
+            station_uuid:      take_or(&mut row, "StationUuid", "".to_string())?,
+            name:              take_or(&mut row, "Name", "".to_string())?,
+            url:               take_or(&mut row, "Url", "".to_string())?,
+            score:             take_or(&mut row, "Score", 0)?,
+            underruns:         take_or(&mut row, "Underruns", 0)?,
+            disconnects:       take_or(&mut row, "Disconnects", 0)?,
+            failed_reconnects: take_or(&mut row, "FailedReconnects", 0)?,
+            check_time:        take_or(&mut row, "CheckTime", "".to_string())?,
+        });
     }
+    Ok(list)
+}
 
-    let query = format!("DELETE FROM StationCheck WHERE CheckTime < NOW() - INTERVAL {} HOUR", hours);
-    let mut my_stmt = pool.prepare(query).unwrap();
-    let result = my_stmt.execute(());
-    match result {
-        Ok(_) => {},
-        Err(err) => {println!("{}",err);}
+/// Stations marked as suspicious by the stream check
+pub fn get_suspicious_report(pool: &mysql::Pool) -> Result<Vec<SuspiciousReportItem>, DbError> {
+    let query = "SELECT StationUuid,Name,Url,UrlCache,Suspicious FROM Station WHERE Suspicious IS NOT NULL ORDER BY Name";
+    let results = pool.prep_exec(query, ())?;
+    let mut list = vec![];
+    for row_ in results {
+        let mut row = row_?;
+        list.push(SuspiciousReportItem {
+            station_uuid:    take_or(&mut row, "StationUuid", "".to_string())?,
+            name:            take_or(&mut row, "Name", "".to_string())?,
+            url:             take_or(&mut row, "Url", "".to_string())?,
+            urlcache:        take_or(&mut row, "UrlCache", "".to_string())?,
+            reason:          take_or(&mut row, "Suspicious", "".to_string())?,
+        });
     }
+    Ok(list)
 }
 
-pub fn delete_old_clicks(pool: &mysql::Pool, hours: u32) {
-    let query = format!("DELETE FROM StationClick WHERE ClickTimestamp < NOW() - INTERVAL {} HOUR", hours);
-    let mut my_stmt = pool.prepare(query).unwrap();
-    let result = my_stmt.execute(());
-    match result {
-        Ok(_) => {},
-        Err(err) => {println!("{}",err);}
+/// Remove the suspicious mark of a station. UrlCache is emptied, so the
+/// stream found by the next check is accepted. Returns false if the
+/// station was not marked.
+pub fn clear_suspicious(pool: &mysql::Pool, station_uuid: &str) -> Result<bool, DbError> {
+    let result = pool.prep_exec("UPDATE Station SET Suspicious=NULL,UrlCache='' WHERE StationUuid=? AND Suspicious IS NOT NULL", (station_uuid,))?;
+    Ok(result.affected_rows() > 0)
+}
+
+fn tls_query(count: usize) -> String {
+    format!("REPLACE INTO StationTlsCheck(StationUuid,Source,Target,Host,Status,Issuer,Subject,NotAfter,HostnameMatch,ChainValid,Error,CheckTime) VALUES{}", placeholders(count, "(?,?,?,?,?,?,?,NOW() + INTERVAL ? SECOND,?,?,?,NOW())"))
+}
+
+/// Values for `tls_query` of (station uuid, source, tls) tuples, NotAfter
+/// is NULL if there was no certificate.
+fn tls_values(target: &str, items: &[(&String, &String, &TlsItem)]) -> Vec<Value> {
+    let mut params: Vec<Value> = vec![];
+    for &(station_uuid, source, tls) in items.iter() {
+        params.push(station_uuid.clone().into());
+        params.push(source.clone().into());
+        params.push(target.into());
+        params.push(tls.host.clone().into());
+        params.push(tls.status.clone().into());
+        params.push(tls.issuer.clone().into());
+        params.push(tls.subject.clone().into());
+        if tls.not_after.is_empty() {
+            params.push(Value::NULL);
+        } else {
+            params.push(tls.expires_in_secs.into());
+        }
+        params.push(tls.hostname_match.into());
+        params.push(tls.chain_valid.into());
+        params.push(tls.error.clone().into());
+    }
+    params
+}
+
+/// Certificates that expire within `days` or are not valid
+pub fn get_tls_report(pool: &mysql::Pool, days: u32) -> Result<Vec<TlsReportItem>, DbError> {
+    let query = "SELECT t.StationUuid,s.Name,t.Target,t.Host,t.Status,t.Issuer,DATE_FORMAT(t.NotAfter,'%Y-%m-%d %H:%i:%s') AS NotAfter,t.Error FROM StationTlsCheck t JOIN Station s ON s.StationUuid=t.StationUuid WHERE t.Status<>'valid' OR t.NotAfter < NOW() + INTERVAL ? DAY ORDER BY t.NotAfter";
+    let results = pool.prep_exec(query, (days,))?;
+    let mut list = vec![];
+    for row_ in results {
+        let mut row = row_?;
+        list.push(TlsReportItem {
+            station_uuid:    take_or(&mut row, "StationUuid", "".to_string())?,
+            name:            take_or(&mut row, "Name", "".to_string())?,
+            target:          take_or(&mut row, "Target", "".to_string())?,
+            host:            take_or(&mut row, "Host", "".to_string())?,
+            status:          take_or(&mut row, "Status", "".to_string())?,
+            issuer:          take_or(&mut row, "Issuer", "".to_string())?,
+            not_after:       take_or(&mut row, "NotAfter", "".to_string())?,
+            error:           take_or(&mut row, "Error", "".to_string())?,
+        });
+    }
+    Ok(list)
+}
+
+/// Found https upgrades, applied ones only if `applied` is true
+pub fn get_https_upgrades(pool: &mysql::Pool, applied: bool) -> Result<Vec<HttpsUpgradeReportItem>, DbError> {
+    let query = "SELECT u.StationUuid,s.Name,u.OldUrl,u.NewUrl,u.Applied,DATE_FORMAT(u.CheckTime,'%Y-%m-%d %H:%i:%s') AS CheckTime FROM StationHttpsUpgrade u JOIN Station s ON s.StationUuid=u.StationUuid WHERE u.Applied=0 OR ? ORDER BY s.Name";
+    let results = pool.prep_exec(query, (applied,))?;
+    let mut list = vec![];
+    for row_ in results {
+        let mut row = row_?;
+        let applied: i32 = take_or(&mut row, "Applied", 0)?;
+        list.push(HttpsUpgradeReportItem {
+            station_uuid:    take_or(&mut row, "StationUuid", "".to_string())?,
+            name:            take_or(&mut row, "Name", "".to_string())?,
+            old_url:         take_or(&mut row, "OldUrl", "".to_string())?,
+            new_url:         take_or(&mut row, "NewUrl", "".to_string())?,
+            applied:         applied != 0,
+            check_time:      take_or(&mut row, "CheckTime", "".to_string())?,
+        });
+    }
+    Ok(list)
+}
+
+/// Fingerprints captured since `since_millis` (unix time), oldest first
+pub fn get_fingerprints(pool: &mysql::Pool, since_millis: i64) -> Result<Vec<FingerprintReportItem>, DbError> {
+    let query = "SELECT f.StationUuid,s.Name,s.Url,f.CaptureMillis,f.Fingerprint FROM StationFingerprint f JOIN Station s ON s.StationUuid=f.StationUuid WHERE f.CaptureMillis>=? ORDER BY f.CaptureMillis";
+    let results = pool.prep_exec(query, (since_millis,))?;
+    let mut list = vec![];
+    for row_ in results {
+        let mut row = row_?;
+        list.push(FingerprintReportItem {
+            station_uuid:    take_or(&mut row, "StationUuid", "".to_string())?,
+            name:            take_or(&mut row, "Name", "".to_string())?,
+            url:             take_or(&mut row, "Url", "".to_string())?,
+            capture_millis:  take_or(&mut row, "CaptureMillis", 0)?,
+            fingerprint:     take_or(&mut row, "Fingerprint", "".to_string())?,
+        });
+    }
+    Ok(list)
+}
+
+pub fn delete_old_fingerprints(pool: &mysql::Pool, before_millis: i64) -> Result<u64, DbError> {
+    execute(pool, "DELETE FROM StationFingerprint WHERE CaptureMillis < ?", (before_millis,))
+}
+
+pub fn get_tls_count_expiring(pool: &mysql::Pool, days: u32) -> Result<u32, DbError> {
+    get_count(pool, "SELECT COUNT(*) AS Items FROM StationTlsCheck WHERE NotAfter < NOW() + INTERVAL ? DAY", (days,))
+}
+
+/// Latest homepage check results of all sources, only stations with
+/// the given status or all stations that are not ok.
+pub fn get_homepage_report(pool: &mysql::Pool, status: Option<&str>) -> Result<Vec<HomepageReportItem>, DbError> {
+    let query = "SELECT h.StationUuid,s.Name,s.Homepage,h.Status,h.HttpStatus,h.FinalUrl,h.Title,DATE_FORMAT(h.CheckTime,'%Y-%m-%d %H:%i:%s') AS CheckTime FROM StationHomepageCheck h JOIN Station s ON s.StationUuid=h.StationUuid WHERE (? IS NULL AND h.Status<>'ok') OR h.Status=? ORDER BY h.Status,s.Name";
+    let results = pool.prep_exec(query, (status, status))?;
+    let mut list = vec![];
+    for row_ in results {
+        let mut row = row_?;
+        list.push(HomepageReportItem {
+            station_uuid:    take_or(&mut row, "StationUuid", "".to_string())?,
+            name:            take_or(&mut row, "Name", "".to_string())?,
+            homepage:        take_or(&mut row, "Homepage", "".to_string())?,
+            status:          take_or(&mut row, "Status", "".to_string())?,
+            http_status:     take_or(&mut row, "HttpStatus", 0)?,
+            final_url:       take_or(&mut row, "FinalUrl", "".to_string())?,
+            title:           take_or(&mut row, "Title", "".to_string())?,
+            check_time:      take_or(&mut row, "CheckTime", "".to_string())?,
+        });
+    }
+    Ok(list)
+}
+
+/// All favicon mirror paths that are in use by stations
+pub fn get_favicon_mirrors(pool: &mysql::Pool) -> Result<HashSet<String>, DbError> {
+    let results = pool.prep_exec("SELECT DISTINCT FaviconMirror FROM Station WHERE FaviconMirror IS NOT NULL AND FaviconMirror<>''", ())?;
+    let mut mirrors = HashSet::new();
+    for row_ in results {
+        let mut row = row_?;
+        mirrors.insert(take_or(&mut row, "FaviconMirror", "".to_string())?);
+    }
+    Ok(mirrors)
+}
+
+pub fn get_favicon_history(pool: &mysql::Pool, station_uuid: &str) -> Result<Vec<FaviconHistoryItem>, DbError> {
+    let query = "SELECT Id,StationUuid,Source,OldFavicon,NewFavicon,Width,Height,DATE_FORMAT(ChangeTime,'%Y-%m-%d %H:%i:%s') AS ChangeTime FROM StationFaviconHistory WHERE StationUuid=? ORDER BY Id";
+    let results = pool.prep_exec(query, (station_uuid,))?;
+    let mut list = vec![];
+    for row_ in results {
+        let mut row = row_?;
+        list.push(FaviconHistoryItem {
+            id:              take_or(&mut row, "Id", 0)?,
+            station_uuid:    take_or(&mut row, "StationUuid", "".to_string())?,
+            source:          take_or(&mut row, "Source", "".to_string())?,
+            old_favicon:     take_or(&mut row, "OldFavicon", "".to_string())?,
+            new_favicon:     take_or(&mut row, "NewFavicon", "".to_string())?,
+            width:           take_or(&mut row, "Width", 0)?,
+            height:          take_or(&mut row, "Height", 0)?,
+            change_time:     take_or(&mut row, "ChangeTime", "".to_string())?,
+        });
     }
+    Ok(list)
 }
 
-pub fn new(connection_str: &str) -> Result<mysql::Pool, Box<Error>> {
+/// Set the favicon of the station back to the old favicon of a history
+/// entry and pin it, so the favicon check does not change it again. The
+/// favicon is only reverted if it is still the one set by the entry. The
+/// revert itself is recorded in the history with source "revert".
+pub fn revert_favicon(pool: &mysql::Pool, history_id: i32) -> Result<FaviconRevert, DbError> {
+    let mut transaction = pool.start_transaction(false, None, None)?;
+    let row: Option<mysql::Row> = transaction.first_exec("SELECT StationUuid,OldFavicon,NewFavicon FROM StationFaviconHistory WHERE Id=?", (history_id,))?;
+    let mut row = match row {
+        Some(row) => row,
+        None => return Ok(FaviconRevert::NotFound),
+    };
+    let station_uuid: String = take_or(&mut row, "StationUuid", "".to_string())?;
+    let old_favicon: String = take_or(&mut row, "OldFavicon", "".to_string())?;
+    let new_favicon: String = take_or(&mut row, "NewFavicon", "".to_string())?;
+
+    let updated = transaction
+        .prep_exec("UPDATE Station SET Favicon=?,FaviconWidth=NULL,FaviconHeight=NULL,FaviconMirror=NULL,FaviconPinned=1 WHERE StationUuid=? AND Favicon=?", (&old_favicon, &station_uuid, &new_favicon))?
+        .affected_rows();
+    if updated == 0 {
+        let current: Option<String> = transaction.first_exec("SELECT Favicon FROM Station WHERE StationUuid=?", (&station_uuid,))?.map(mysql::from_row);
+        transaction.rollback()?;
+        return Ok(FaviconRevert::Changed(current.unwrap_or_default()));
+    }
+    transaction.prep_exec("INSERT INTO StationFaviconHistory(StationUuid,Source,OldFavicon,NewFavicon,ChangeTime) VALUES(?,'revert',?,?,NOW())", (&station_uuid, &new_favicon, &old_favicon))?;
+    transaction.commit()?;
+    Ok(FaviconRevert::Reverted(FaviconHistoryItem {
+        id: history_id,
+        station_uuid,
+        source: String::from("revert"),
+        old_favicon: new_favicon,
+        new_favicon: old_favicon,
+        width: 0,
+        height: 0,
+        change_time: String::from(""),
+    }))
+}
+
+/// Let the favicon check change the favicon of the station again, returns
+/// false if the station does not exist
+pub fn unpin_favicon(pool: &mysql::Pool, station_uuid: &str) -> Result<bool, DbError> {
+    let result = pool.prep_exec("UPDATE Station SET FaviconPinned=0 WHERE StationUuid=?", (station_uuid,))?;
+    if result.affected_rows() > 0 {
+        return Ok(true);
+    }
+    let exists: Option<u64> = pool.first_exec("SELECT COUNT(*) FROM Station WHERE StationUuid=?", (station_uuid,))?.map(mysql::from_row);
+    Ok(exists.unwrap_or(0) > 0)
+}
+
+fn check_values(item: &StationCheckItemNew, check_uuid: &str) -> Vec<Value> {
+    let mut values: Vec<Value> = vec![
+        item.station_uuid.clone().into(),
+        check_uuid.into(),
+        item.source.clone().into(),
+        item.codec.clone().into(),
+        item.bitrate.into(),
+        item.hls.into(),
+        item.check_ok.into(),
+        item.url.clone().into(),
+    ];
+    match item.audio {
+        Some(ref audio) => {
+            values.push(audio.rms_db.into());
+            values.push(audio.peak_db.into());
+            values.push(audio.silence_secs.into());
+            values.push(audio.silent.into());
+        }
+        None => {
+            values.extend(vec![Value::NULL; 4]);
+        }
+    }
+    match item.measured_bitrate {
+        Some(ref bitrate) => {
+            values.push(bitrate.measured.into());
+            values.push(bitrate.mismatch.into());
+        }
+        None => {
+            values.extend(vec![Value::NULL; 2]);
+        }
+    }
+    values.push(item.dash.into());
+    values
+}
+
+/// Comma separated list of `count` placeholder groups, e.g. "(?,?),(?,?)"
+fn placeholders(count: usize, group: &str) -> String {
+    vec![group; count].join(",")
+}
+
+pub fn delete_old_checks(pool: &mysql::Pool, hours: u32) -> Result<u64, DbError> {
+    let query = format!("DELETE FROM StationCheckHistory WHERE CheckTime < NOW() - INTERVAL {} HOUR", hours);
+    let history = execute(pool, &query, ())?;
+
+    let query = format!("DELETE FROM StationCheck WHERE CheckTime < NOW() - INTERVAL {} HOUR", hours);
+    let current = execute(pool, &query, ())?;
+
+    let query = format!("DELETE v FROM StationHlsVariant v JOIN StationHlsCheck h ON h.CheckUuid=v.CheckUuid WHERE h.CheckTime < NOW() - INTERVAL {} HOUR", hours);
+    execute(pool, &query, ())?;
+    let query = format!("DELETE FROM StationHlsCheck WHERE CheckTime < NOW() - INTERVAL {} HOUR", hours);
+    execute(pool, &query, ())?;
+
+    let query = format!("DELETE r FROM StationDashRepresentation r JOIN StationDashCheck d ON d.CheckUuid=r.CheckUuid WHERE d.CheckTime < NOW() - INTERVAL {} HOUR", hours);
+    execute(pool, &query, ())?;
+    let query = format!("DELETE FROM StationDashCheck WHERE CheckTime < NOW() - INTERVAL {} HOUR", hours);
+    execute(pool, &query, ())?;
+
+    let query = format!("DELETE FROM StationRegionCheck WHERE CheckTime < NOW() - INTERVAL {} HOUR", hours);
+    execute(pool, &query, ())?;
+    Ok(history + current)
+}
+
+pub fn delete_old_clicks(pool: &mysql::Pool, hours: u32) -> Result<u64, DbError> {
+    let query = format!("DELETE FROM StationClick WHERE ClickTimestamp < NOW() - INTERVAL {} HOUR", hours);
+    execute(pool, &query, ())
+}
+
+pub fn new(connection_str: &str) -> Result<mysql::Pool, DbError> {
     let pool = mysql::Pool::new(connection_str)?;
     Ok(pool)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_error(code: u16) -> DbError {
+        DbError::Query(mysql::Error::MySqlError(mysql::MySqlError {
+            state: String::from("HY000"),
+            message: String::from("error"),
+            code,
+        }))
+    }
+
+    #[test]
+    fn lost_connections_and_lock_errors_are_transient() {
+        for &code in &[1205, 1213, 2006, 2013] {
+            assert!(server_error(code).is_transient(), "code {}", code);
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_not_transient() {
+        // ER_PARSE_ERROR
+        assert!(!server_error(1064).is_transient());
+        assert!(!DbError::Decode(String::from("column missing")).is_transient());
+    }
+
+    #[test]
+    fn retry_gives_up_after_the_attempts() {
+        let mut calls = 0;
+        let result: Result<(), DbError> = retry_backoff("Test", RETRY_ATTEMPTS, Duration::from_millis(1), || {
+            calls += 1;
+            Err(server_error(1213))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, RETRY_ATTEMPTS);
+    }
+
+    #[test]
+    fn retry_returns_permanent_errors_at_once() {
+        let mut calls = 0;
+        let result: Result<(), DbError> = retry_backoff("Test", RETRY_ATTEMPTS, Duration::from_millis(1), || {
+            calls += 1;
+            Err(server_error(1064))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn retry_returns_the_first_success() {
+        let mut calls = 0;
+        let result = retry_backoff("Test", RETRY_ATTEMPTS, Duration::from_millis(1), || {
+            calls += 1;
+            if calls < 3 { Err(server_error(2006)) } else { Ok(calls) }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn station_updates_share_one_statement() {
+        let a = String::from("a");
+        let b = String::from("b");
+        let rows = vec![
+            (&a, vec![Some(Value::from("MP3")), Some(Value::from("http://a/"))]),
+            (&b, vec![Some(Value::from("AAC")), None]),
+        ];
+        let (query, params) = update_stations_query("LastCheckTime=NOW()", &["Codec", "UrlCache"], &rows);
+        assert_eq!(query, "UPDATE Station SET LastCheckTime=NOW(),Codec=CASE StationUuid WHEN ? THEN ? WHEN ? THEN ? ELSE Codec END,UrlCache=CASE StationUuid WHEN ? THEN ? ELSE UrlCache END WHERE StationUuid IN (?,?)");
+        assert_eq!(params, vec![
+            Value::from("a"), Value::from("MP3"), Value::from("b"), Value::from("AAC"),
+            Value::from("a"), Value::from("http://a/"),
+            Value::from("a"), Value::from("b"),
+        ]);
+    }
+
+    #[test]
+    fn station_updates_leave_out_unset_columns() {
+        let a = String::from("a");
+        let rows = vec![(&a, vec![None])];
+        let (query, params) = update_stations_query("LastCheckOk=0", &["UrlCache"], &rows);
+        assert_eq!(query, "UPDATE Station SET LastCheckOk=0 WHERE StationUuid IN (?)");
+        assert_eq!(params, vec![Value::from("a")]);
+    }
+}
@@ -1,81 +1,512 @@
-use reqwest;
+use image;
+use image::{ImageDecoder, ImageFormat, ImageResult};
 use reqwest::header::CONTENT_TYPE;
-use reqwest::header::USER_AGENT;
-use std::time::Duration;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use url::Url;
 use website_icon_extract;
 
-pub fn check(
-    homepage: &str,
-    old_favicon: &str,
-    verbosity: u8,
-    useragent: &str,
-    timeout: u32,
-) -> String {
-    let check = check_url(old_favicon, useragent, timeout);
-    if !check {
-        if verbosity > 0 {
-            println!("Check for favicon: {}", homepage);
-        }
-        let icons = website_icon_extract::extract_icons(homepage, useragent, timeout);
-        match icons {
-            Ok(icons) => {
-                if icons.len() > 0 {
-                    if verbosity > 0 {
-                        println!("Favicon {}", icons[0]);
-                    }
-                    return icons[0].clone();
+use config::Config;
+use http::HttpClient;
+use mirror;
+use models::FaviconItem;
+
+/// Bounds for the pixel width and height of a valid favicon
+const MIN_ICON_DIMENSION: u32 = 1;
+const MAX_ICON_DIMENSION: u32 = 4096;
+/// Pixel size assumed for scalable icons (svg) when comparing candidates,
+/// larger raster icons are not considered better than this.
+const SCALABLE_SIZE: u32 = 512;
+/// Homepages are only read up to this size when looking for icons
+const MAX_PAGE_BYTES: u64 = 1024 * 1024;
+/// meta names and link rels that reference icons
+const META_ICON_NAMES: &[&str] = &[
+    "msapplication-tileimage",
+    "msapplication-square70x70logo",
+    "msapplication-square150x150logo",
+    "msapplication-square310x310logo",
+    "msapplication-wide310x150logo",
+];
+const LINK_ICON_RELS: &[&str] = &["apple-touch-icon", "shortcut icon", "icon"];
+
+pub fn check(config: &Config, client: &HttpClient, homepage: &str, old_favicon: &str) -> FaviconItem {
+    match select(config, client, homepage, old_favicon) {
+        Some((mut icon, bytes)) => {
+            if !config.favicon_mirror_dir.is_empty() {
+                if icon.format == "svg" {
+                    debug!("Favicon {} not mirrored, svg is not supported", icon.url);
                 } else {
-                    if verbosity > 0 {
-                        println!("No favicons found for: {}", homepage);
+                    match mirror::store(&config.favicon_mirror_dir, &bytes) {
+                        Ok(mirror_path) => {
+                            icon.mirror = mirror_path;
+                        }
+                        Err(e) => {
+                            warn!("Unable to mirror favicon {}: {}", icon.url, e);
+                        }
                     }
                 }
             }
+            icon
+        }
+        None => FaviconItem {
+            url: String::from(""),
+            width: 0,
+            height: 0,
+            format: String::from(""),
+            mirror: String::from(""),
+        },
+    }
+}
+
+/// Returns the favicon to use for the station together with its content.
+/// The homepage itself is fetched by website_icon_extract, which uses its
+/// own client, unless it has to go through the proxy; all icon downloads
+/// go through the shared client.
+fn select(config: &Config, client: &HttpClient, homepage: &str, old_favicon: &str) -> Option<(FaviconItem, Vec<u8>)> {
+    let max_bytes = config.favicon_max_bytes;
+    let old = if old_favicon.is_empty() {
+        None
+    } else {
+        match download_icon(client, old_favicon, max_bytes) {
+            Ok(icon) => Some(icon),
             Err(e) => {
-                if verbosity > 0 {
-                    println!("Favicon error ({}): {}", homepage, e.to_string());
+                debug!("Favicon {} is not valid: {}", old_favicon, e);
+                None
+            }
+        }
+    };
+    // a usable old favicon is only replaced by a strictly better one, it
+    // comes first and wins ties
+    let mut candidates: Vec<(FaviconItem, Vec<u8>)> = vec![];
+    let mut fallback = None;
+    match old {
+        Some(icon) if rate(config, &icon.0).is_some() => {
+            if !config.favicon_replace {
+                return Some(icon);
+            }
+            candidates.push(icon);
+        }
+        old => fallback = old,
+    }
+
+    debug!("Check for favicon: {}", homepage);
+    let icons = match client.proxy() {
+        Some(proxy) if proxy.applies_to_url(homepage) => extract_icons(client, homepage),
+        _ => website_icon_extract::extract_icons(homepage, &config.useragent, config.tcp_timeout).map_err(|e| e.to_string()),
+    };
+    match icons {
+        Ok(icons) => {
+            candidates.extend(icons.iter().filter_map(|url| match download_icon(client, url, max_bytes) {
+                Ok((icon, bytes)) => {
+                    debug!("Favicon candidate {} {}x{} {}", icon.url, icon.width, icon.height, icon.format);
+                    Some((icon, bytes))
+                }
+                Err(e) => {
+                    debug!("Favicon candidate {} ignored: {}", url, e);
+                    None
                 }
+            }));
+        }
+        Err(e) => {
+            debug!("Favicon error ({}): {}", homepage, e);
+        }
+    }
+    match choose_best(config, candidates) {
+        Some((icon, bytes)) => {
+            debug!("Favicon {} {}x{} {}", icon.url, icon.width, icon.height, icon.format);
+            Some((icon, bytes))
+        }
+        None => {
+            debug!("No usable favicons found for: {}", homepage);
+            // keep a valid old favicon, even if it does not match the preferences
+            fallback
+        }
+    }
+}
+
+/// Icons referenced by the homepage (link rel icons, open graph and tile
+/// images) and /favicon.ico, as found by website_icon_extract
+fn extract_icons(client: &HttpClient, homepage: &str) -> Result<Vec<String>, String> {
+    let (final_url, content) = client.get(homepage, |res| {
+        if !res.status().is_success() {
+            return Err(format!("http status {}", res.status()));
+        }
+        let final_url = res.url().to_string();
+        let is_html = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .map(|t| t.starts_with("text/html"))
+            .unwrap_or(false);
+        let mut bytes = vec![];
+        if is_html {
+            res.take(MAX_PAGE_BYTES)
+                .read_to_end(&mut bytes)
+                .map_err(|e| e.to_string())?;
+        }
+        Ok((final_url, String::from_utf8_lossy(&bytes).to_string()))
+    })?;
+    let mut links = icon_links(&content);
+    links.push(String::from("/favicon.ico"));
+    let base = Url::parse(&final_url).map_err(|e| e.to_string())?;
+    let mut icons: Vec<String> = vec![];
+    for link in links.iter().filter(|link| !link.is_empty()) {
+        if let Ok(icon) = base.join(link) {
+            let icon = icon.to_string();
+            if !icons.contains(&icon) {
+                icons.push(icon);
             }
         }
-        String::from("")
+    }
+    Ok(icons)
+}
+
+/// Icon links of a html page, not resolved
+fn icon_links(content: &str) -> Vec<String> {
+    let mut links = vec![];
+    let mut reader = Reader::from_str(content);
+    reader.trim_text(true);
+    reader.check_end_names(false);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) => {
+                let name = e.local_name().to_ascii_lowercase();
+                if name == b"meta" || name == b"link" {
+                    let attrs: HashMap<Vec<u8>, String> = e
+                        .attributes()
+                        .filter_map(|attr| attr.ok())
+                        .map(|attr| (attr.key.to_ascii_lowercase(), String::from_utf8_lossy(&attr.value).to_string()))
+                        .collect();
+                    let attr = |key: &[u8]| attrs.get(key).map(|v| v.trim().to_lowercase()).unwrap_or_default();
+                    let link = if name == b"meta" {
+                        let is_icon = META_ICON_NAMES.contains(&attr(b"name").as_str()) || attr(b"property") == "og:image";
+                        if is_icon { attrs.get(&b"content"[..]) } else { None }
+                    } else if LINK_ICON_RELS.contains(&attr(b"rel").as_str()) {
+                        attrs.get(&b"href"[..])
+                    } else {
+                        None
+                    };
+                    if let Some(link) = link {
+                        links.push(link.trim().to_string());
+                    }
+                }
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            // html is often not well formed, the links found so far are kept
+            Err(_) => break,
+        }
+        buf.clear();
+    }
+    links
+}
+
+/// Rate an icon according to the configured preferences: icons smaller
+/// than the minimum size, non square icons (if required) and formats not
+/// in the preferred list are not usable. Otherwise returns the effective
+/// size and the rank of the format.
+fn rate(config: &Config, icon: &FaviconItem) -> Option<(u32, usize)> {
+    let rank = config.favicon_formats.iter().position(|f| f == &icon.format)?;
+    let size = if icon.format == "svg" {
+        SCALABLE_SIZE
     } else {
-        String::from(old_favicon)
-    }
-}
-
-fn check_url(url: &str, useragent: &str, timeout: u32) -> bool {
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(timeout.into()))
-        .build();
-    if client.is_err() {
-        return false;
-    }
-    let client = client.unwrap();
-    let res = client
-        .get(url)
-        .header(USER_AGENT, useragent.to_string())
-        .send();
-    match res {
-        Ok(r) => {
-            if r.status().is_success() {
-                let t = r.headers().get(CONTENT_TYPE);
-                match t {
-                    Some(t) => {
-                        let value = t.to_str();
-                        if let Ok(value) = value {
-                            if value.starts_with("image"){
-                                return true;
-                            }
-                        }
+        icon.width.min(icon.height).min(SCALABLE_SIZE)
+    };
+    if icon.format != "svg" && size < config.favicon_min_size {
+        return None;
+    }
+    if config.favicon_square && !is_square(icon) {
+        return None;
+    }
+    Some((size, rank))
+}
+
+/// Pick the best usable icon: the biggest one wins, ties are decided by
+/// the order of preferred formats and then by the order of the candidates.
+fn choose_best(config: &Config, candidates: Vec<(FaviconItem, Vec<u8>)>) -> Option<(FaviconItem, Vec<u8>)> {
+    let mut usable: Vec<(u32, usize, (FaviconItem, Vec<u8>))> = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let (size, rank) = rate(config, &candidate.0)?;
+            Some((size, rank, candidate))
+        })
+        .collect();
+    usable.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    usable.into_iter().next().map(|(_, _, candidate)| candidate)
+}
+
+/// Aspect ratio within 10%, svg icons without size information count as square.
+fn is_square(icon: &FaviconItem) -> bool {
+    if icon.width == 0 || icon.height == 0 {
+        return icon.format == "svg";
+    }
+    let (small, big) = if icon.width < icon.height {
+        (icon.width, icon.height)
+    } else {
+        (icon.height, icon.width)
+    };
+    small * 10 >= big * 9
+}
+
+/// Download an icon and decode it to get its real format and size.
+/// The Content-Type header is not trusted, many servers send image types
+/// for error pages or broken files.
+fn download_icon(client: &HttpClient, url: &str, max_bytes: u64) -> Result<(FaviconItem, Vec<u8>), String> {
+    let (content_type, bytes) = client.get(url, |res| {
+        if !res.status().is_success() {
+            return Err(format!("http status {}", res.status()));
+        }
+        let content_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        let mut bytes = vec![];
+        res.take(max_bytes + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())?;
+        Ok((content_type, bytes))
+    })?;
+    if bytes.is_empty() {
+        return Err(String::from("empty content"));
+    }
+    if bytes.len() as u64 > max_bytes {
+        return Err(format!("larger than {} bytes", max_bytes));
+    }
+    let icon = decode_icon(url, &bytes)?;
+    if !content_type.starts_with("image") {
+        debug!("Favicon {} is {} but sent as '{}'", url, icon.format, content_type);
+    }
+    if icon.format == "svg" && icon.width > 0 {
+        check_dimensions(u64::from(icon.width), u64::from(icon.height))?;
+    }
+    Ok((icon, bytes))
+}
+
+fn check_dimensions(width: u64, height: u64) -> Result<(), String> {
+    let size_ok = |v: u64| (u64::from(MIN_ICON_DIMENSION)..=u64::from(MAX_ICON_DIMENSION)).contains(&v);
+    if !size_ok(width) || !size_ok(height) {
+        return Err(format!("illegal dimensions {}x{}", width, height));
+    }
+    Ok(())
+}
+
+fn decode_icon(url: &str, bytes: &[u8]) -> Result<FaviconItem, String> {
+    if looks_like_xml(bytes) {
+        let (width, height) = svg_size(bytes)?;
+        return Ok(FaviconItem {
+            url: String::from(url),
+            width,
+            height,
+            format: String::from("svg"),
+            mirror: String::from(""),
+        });
+    }
+    let format = image::guess_format(bytes).map_err(|e| e.to_string())?;
+    let format_name = match format {
+        ImageFormat::PNG => "png",
+        ImageFormat::JPEG => "jpeg",
+        ImageFormat::GIF => "gif",
+        ImageFormat::WEBP => "webp",
+        ImageFormat::ICO => "ico",
+        ImageFormat::BMP => "bmp",
+        _ => {
+            return Err(format!("unsupported image format {:?}", format));
+        }
+    };
+    // the size from the header is checked before the pixels are decoded,
+    // a small file may claim a huge image
+    let (width, height) = image_size(format, bytes).map_err(|e| e.to_string())?;
+    check_dimensions(width, height)?;
+    // decode completely to reject broken files
+    image::load_from_memory_with_format(bytes, format).map_err(|e| e.to_string())?;
+    Ok(FaviconItem {
+        url: String::from(url),
+        width: width as u32,
+        height: height as u32,
+        format: String::from(format_name),
+        mirror: String::from(""),
+    })
+}
+
+/// Width and height from the image header, without decoding the pixels
+fn image_size(format: ImageFormat, bytes: &[u8]) -> ImageResult<(u64, u64)> {
+    let reader = Cursor::new(bytes);
+    match format {
+        ImageFormat::PNG => Ok(image::png::PNGDecoder::new(reader)?.dimensions()),
+        ImageFormat::JPEG => Ok(image::jpeg::JPEGDecoder::new(reader)?.dimensions()),
+        ImageFormat::GIF => Ok(image::gif::Decoder::new(reader)?.dimensions()),
+        ImageFormat::ICO => Ok(image::ico::ICODecoder::new(reader)?.dimensions()),
+        ImageFormat::BMP => Ok(image::bmp::BMPDecoder::new(reader)?.dimensions()),
+        // the webp decoder decodes the frame when it is created
+        ImageFormat::WEBP => webp_size(bytes),
+        _ => Err(image::ImageError::UnsupportedError(format!("{:?}", format))),
+    }
+}
+
+/// Size from the first chunk of a webp (RIFF header, chunk name at 12,
+/// chunk data at 20):
+/// - "VP8 " lossy: 3 byte frame tag, start code 9d 01 2a, then 14 bit
+///   width and height
+/// - "VP8L" lossless: signature 2f, then 14 bit width-1 and height-1
+/// - "VP8X" extended: 4 bytes flags, then 24 bit canvas width-1 and height-1
+fn webp_size(bytes: &[u8]) -> ImageResult<(u64, u64)> {
+    let unsupported = || image::ImageError::FormatError(String::from("unsupported webp header"));
+    if bytes.len() < 30 {
+        return Err(unsupported());
+    }
+    let le16 = |at: usize| u64::from(bytes[at]) | u64::from(bytes[at + 1]) << 8;
+    let le24 = |at: usize| le16(at) | u64::from(bytes[at + 2]) << 16;
+    match &bytes[12..16] {
+        b"VP8 " if bytes[23..26] == [0x9d, 0x01, 0x2a] => Ok((le16(26) & 0x3fff, le16(28) & 0x3fff)),
+        b"VP8L" if bytes[20] == 0x2f => {
+            let bits = le24(21) | u64::from(bytes[24]) << 24;
+            Ok(((bits & 0x3fff) + 1, (bits >> 14 & 0x3fff) + 1))
+        }
+        b"VP8X" => Ok((le24(24) + 1, le24(27) + 1)),
+        _ => Err(unsupported()),
+    }
+}
+
+fn looks_like_xml(bytes: &[u8]) -> bool {
+    let start = &bytes[..bytes.len().min(512)];
+    String::from_utf8_lossy(start).trim_start().starts_with('<')
+}
+
+/// Parse an svg document completely and return the size from the
+/// width/height attributes or the viewBox of the root element, (0, 0) if
+/// it has none. Fails for broken xml and for documents that are not svg
+/// (e.g. html error pages).
+fn svg_size(bytes: &[u8]) -> Result<(u32, u32), String> {
+    let mut reader = Reader::from_reader(bytes);
+    reader.check_end_names(true);
+    let mut buf = Vec::new();
+    let mut size: Option<(u32, u32)> = None;
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) => {
+                if size.is_none() {
+                    if e.local_name() != b"svg" {
+                        return Err(String::from("not an svg document"));
                     }
-                    None => {
-                        return false;
+                    let mut attrs: HashMap<Vec<u8>, String> = HashMap::new();
+                    for attr in e.attributes() {
+                        let attr = attr.map_err(|e| e.to_string())?;
+                        let value = String::from_utf8_lossy(&attr.value).to_string();
+                        attrs.insert(attr.key.to_vec(), value);
                     }
+                    size = Some(svg_root_size(&attrs));
                 }
             }
-            return false;
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => {
+                return Err(format!("broken svg at {}: {}", reader.buffer_position(), e));
+            }
         }
-        Err(_) => {
-            return false;
+        buf.clear();
+    }
+    size.ok_or_else(|| String::from("no svg element"))
+}
+
+fn svg_root_size(attrs: &HashMap<Vec<u8>, String>) -> (u32, u32) {
+    let width = attrs.get(&b"width"[..]).and_then(|v| svg_length(v));
+    let height = attrs.get(&b"height"[..]).and_then(|v| svg_length(v));
+    if let (Some(width), Some(height)) = (width, height) {
+        return (width, height);
+    }
+    if let Some(view_box) = attrs.get(&b"viewBox"[..]) {
+        let values: Vec<f32> = view_box
+            .split(&[' ', ','][..])
+            .filter_map(|v| v.parse().ok())
+            .collect();
+        if values.len() == 4 {
+            return (values[2] as u32, values[3] as u32);
         }
     }
+    (0, 0)
+}
+
+/// Length in pixels, None for relative lengths like "100%"
+fn svg_length(value: &str) -> Option<u32> {
+    let number: String = value.chars().take_while(|c| c.is_ascii_digit() || *c == '.').collect();
+    let unit = value[number.len()..].trim();
+    if !unit.is_empty() && unit != "px" {
+        return None;
+    }
+    let number: f32 = number.parse().ok()?;
+    Some(number as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn svg_size_from_attributes() {
+        let svg = br#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" width="64px" height="32"><rect/></svg>"#;
+        assert_eq!(svg_size(svg), Ok((64, 32)));
+    }
+
+    #[test]
+    fn svg_size_from_view_box() {
+        let svg = br#"<svg width="100%" viewBox="0 0 48,24"></svg>"#;
+        assert_eq!(svg_size(svg), Ok((48, 24)));
+    }
+
+    #[test]
+    fn svg_size_unknown() {
+        assert_eq!(svg_size(b"<svg/>"), Ok((0, 0)));
+    }
+
+    #[test]
+    fn svg_size_rejects_other_documents() {
+        assert!(svg_size(b"<html><body></body></html>").is_err());
+        assert!(svg_size(b"<svg><g></svg>").is_err());
+        assert!(svg_size(b"").is_err());
+    }
+
+    #[test]
+    fn oversized_gif_is_rejected_before_decoding() {
+        // 8192x8192 logical screen, no image data
+        let gif = b"GIF89a\x00\x20\x00\x20\x00\x00\x00\x3b";
+        let err = decode_icon("http://example.com/favicon.gif", gif).unwrap_err();
+        assert_eq!(err, "illegal dimensions 8192x8192");
+    }
+
+    #[test]
+    fn webp_size_from_frame_header() {
+        let mut webp = b"RIFF\x00\x00\x00\x00WEBPVP8 \x00\x00\x00\x00\x00\x00\x00\x9d\x01\x2a".to_vec();
+        webp.extend_from_slice(&[0x00, 0x11, 0x40, 0x00]);
+        assert_eq!(webp_size(&webp).unwrap(), (0x1100, 0x40));
+    }
+
+    #[test]
+    fn webp_size_from_lossless_header() {
+        // 400x300: width-1 = 399 in bits 0-13, height-1 = 299 in bits 14-27
+        let bits: u32 = 399 | 299 << 14;
+        let mut webp = b"RIFF\x00\x00\x00\x00WEBPVP8L\x00\x00\x00\x00\x2f".to_vec();
+        webp.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+        webp.extend_from_slice(&[0; 5]);
+        assert_eq!(webp_size(&webp).unwrap(), (400, 300));
+    }
+
+    #[test]
+    fn webp_size_from_extended_header() {
+        // 512x70000: canvas width-1 and height-1 as 24 bit values
+        let mut webp = b"RIFF\x00\x00\x00\x00WEBPVP8X\x00\x00\x00\x00\x10\x00\x00\x00".to_vec();
+        webp.extend_from_slice(&[0xff, 0x01, 0x00, 0x6f, 0x11, 0x01]);
+        assert_eq!(webp_size(&webp).unwrap(), (512, 70000));
+    }
+
+    #[test]
+    fn webp_size_rejects_unknown_chunks() {
+        let mut webp = b"RIFF\x00\x00\x00\x00WEBPALPH".to_vec();
+        webp.extend_from_slice(&[0; 14]);
+        assert!(webp_size(&webp).is_err());
+    }
 }
@@ -0,0 +1,301 @@
+use std::f32::consts::PI;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use audio::Decoded;
+
+/// Length of one fingerprint frame
+pub const FRAME_MILLIS: i64 = 100;
+/// Energy bands between these frequencies, log spaced
+const BANDS: usize = 17;
+const MIN_FREQUENCY: f32 = 300.0;
+const MAX_FREQUENCY: f32 = 3000.0;
+/// Least number of overlapping frames for a comparison
+const MIN_OVERLAP: usize = 30;
+/// Delay between two urls of the same broadcast, caused by different
+/// encoders, relays and buffers
+pub const MAX_OFFSET_MILLIS: i64 = 30_000;
+
+/// Compute a fingerprint with one 16 bit value per frame. Each bit tells
+/// if the energy difference of two neighbouring bands grew or shrank
+/// compared to the previous frame, which survives different bitrates,
+/// codecs and volume levels of the same broadcast.
+pub fn compute(decoded: &Decoded) -> Vec<u16> {
+    if (decoded.rate as f32) < MAX_FREQUENCY * 2.0 {
+        return vec![];
+    }
+    let frame = (decoded.rate as i64 * FRAME_MILLIS / 1000).max(1) as usize;
+    let frames = decoded.samples.len() / frame;
+    let mut filters: Vec<BandPass> = (0..BANDS)
+        .map(|band| {
+            let low = band_edge(band);
+            let high = band_edge(band + 1);
+            BandPass::new(decoded.rate as f32, (low * high).sqrt(), high - low)
+        })
+        .collect();
+    let mut energies = vec![[0.0f32; BANDS]; frames];
+    for (energy, chunk) in energies.iter_mut().zip(decoded.samples.chunks(frame)) {
+        for (band, filter) in filters.iter_mut().enumerate() {
+            energy[band] = chunk.iter().map(|s| {
+                let v = filter.process(*s);
+                v * v
+            }).sum();
+        }
+    }
+    let mut fingerprint = vec![];
+    for index in 1..frames {
+        let mut bits: u16 = 0;
+        for band in 0..BANDS - 1 {
+            let now = energies[index][band] - energies[index][band + 1];
+            let before = energies[index - 1][band] - energies[index - 1][band + 1];
+            if now - before > 0.0 {
+                bits |= 1 << band;
+            }
+        }
+        fingerprint.push(bits);
+    }
+    fingerprint
+}
+
+/// Current unix time in milliseconds, the time base of fingerprints
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn band_edge(index: usize) -> f32 {
+    MIN_FREQUENCY * (MAX_FREQUENCY / MIN_FREQUENCY).powf(index as f32 / BANDS as f32)
+}
+
+/// Best similarity (share of equal bits) of two fingerprints, trying all
+/// alignments within `max_offset_ms` around the difference of their
+/// start times. None if they do not overlap long enough.
+pub fn similarity(a: &[u16], a_start_ms: i64, b: &[u16], b_start_ms: i64, max_offset_ms: i64) -> Option<f32> {
+    let expected = (b_start_ms - a_start_ms) / FRAME_MILLIS;
+    let max_offset = max_offset_ms / FRAME_MILLIS;
+    let mut best: Option<f32> = None;
+    // frame i of b is compared to frame i + shift of a
+    for shift in expected - max_offset..=expected + max_offset {
+        let mut equal_bits = 0;
+        let mut overlap = 0;
+        for (i, bits_b) in b.iter().enumerate() {
+            let j = i as i64 + shift;
+            if j < 0 || j >= a.len() as i64 {
+                continue;
+            }
+            equal_bits += 16 - (a[j as usize] ^ bits_b).count_ones();
+            overlap += 1;
+        }
+        if overlap < MIN_OVERLAP {
+            continue;
+        }
+        let value = equal_bits as f32 / (overlap * 16) as f32;
+        best = match best {
+            Some(best) if best >= value => Some(best),
+            _ => Some(value),
+        };
+    }
+    best
+}
+
+/// Group fingerprints of different stations that match each other into
+/// clusters of probable duplicates. Items are (station uuid, start of
+/// the capture in unix millis, fingerprint). Returns the station uuids
+/// of every cluster with the best similarity found in it.
+pub fn clusters(items: &[(String, i64, Vec<u16>)], max_offset_ms: i64, min_similarity: f32) -> Vec<(Vec<String>, f32)> {
+    let mut order: Vec<usize> = (0..items.len()).collect();
+    order.sort_by_key(|&i| items[i].1);
+    let mut parents: Vec<usize> = (0..items.len()).collect();
+    let mut best = vec![0.0f32; items.len()];
+    for (position, &i) in order.iter().enumerate() {
+        let (ref uuid_a, start_a, ref a) = items[i];
+        let end_a = start_a + a.len() as i64 * FRAME_MILLIS + max_offset_ms;
+        for &j in order[position + 1..].iter() {
+            let (ref uuid_b, start_b, ref b) = items[j];
+            if start_b > end_a {
+                break;
+            }
+            if uuid_a == uuid_b {
+                continue;
+            }
+            if let Some(value) = similarity(a, start_a, b, start_b, max_offset_ms) {
+                if value >= min_similarity {
+                    let root = union(&mut parents, i, j);
+                    best[root] = best[root].max(best[i]).max(best[j]).max(value);
+                }
+            }
+        }
+    }
+
+    let mut groups: Vec<(usize, Vec<String>)> = vec![];
+    for (i, item) in items.iter().enumerate() {
+        let root = find(&mut parents, i);
+        let uuid = &item.0;
+        match groups.iter_mut().find(|group| group.0 == root) {
+            Some(group) => {
+                if !group.1.contains(uuid) {
+                    group.1.push(uuid.clone());
+                }
+            }
+            None => groups.push((root, vec![uuid.clone()])),
+        }
+    }
+    groups
+        .into_iter()
+        .filter(|group| group.1.len() > 1)
+        .map(|(root, uuids)| (uuids, best[root]))
+        .collect()
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    let mut node = i;
+    while parents[node] != root {
+        let next = parents[node];
+        parents[node] = root;
+        node = next;
+    }
+    root
+}
+
+/// Join the sets of i and j, returns the new root
+fn union(parents: &mut [usize], i: usize, j: usize) -> usize {
+    let root_i = find(parents, i);
+    let root_j = find(parents, j);
+    parents[root_j] = root_i;
+    root_i
+}
+
+pub fn to_hex(fingerprint: &[u16]) -> String {
+    fingerprint.iter().map(|bits| format!("{:04x}", bits)).collect()
+}
+
+pub fn from_hex(value: &str) -> Vec<u16> {
+    value
+        .as_bytes()
+        .chunks(4)
+        .filter_map(|chunk| ::std::str::from_utf8(chunk).ok())
+        .filter_map(|chunk| u16::from_str_radix(chunk, 16).ok())
+        .collect()
+}
+
+/// Biquad band pass filter (constant peak gain)
+struct BandPass {
+    b0: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl BandPass {
+    fn new(rate: f32, center: f32, bandwidth: f32) -> BandPass {
+        let w0 = 2.0 * PI * center / rate;
+        let q = center / bandwidth;
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        BandPass {
+            b0: alpha / a0,
+            b2: -alpha / a0,
+            a1: -2.0 * w0.cos() / a0,
+            a2: (1.0 - alpha) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic noise, unrelated fingerprints match about half of
+    /// their bits
+    fn noise(seed: u32, len: usize) -> Vec<u16> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn similarity_of_equal_fingerprints() {
+        let a = noise(1, 100);
+        assert_eq!(similarity(&a, 0, &a, 0, MAX_OFFSET_MILLIS), Some(1.0));
+    }
+
+    #[test]
+    fn similarity_finds_delayed_copy() {
+        let a = noise(1, 100);
+        // b starts 1s later
+        let b = a[10..].to_vec();
+        assert_eq!(similarity(&a, 0, &b, 1000, MAX_OFFSET_MILLIS), Some(1.0));
+        // b starts at the same time, but is 2s behind in the broadcast
+        let delayed = a[..80].to_vec();
+        assert_eq!(similarity(&a, 0, &delayed, 2000, MAX_OFFSET_MILLIS), Some(1.0));
+    }
+
+    #[test]
+    fn similarity_of_unrelated_fingerprints() {
+        let value = similarity(&noise(1, 100), 0, &noise(2, 100), 0, MAX_OFFSET_MILLIS).unwrap();
+        assert!(value < 0.7, "similarity {}", value);
+    }
+
+    #[test]
+    fn similarity_needs_overlap() {
+        let a = noise(1, 100);
+        assert_eq!(similarity(&a, 0, &a[..MIN_OVERLAP - 1], 0, MAX_OFFSET_MILLIS), None);
+        // too far apart in time
+        assert_eq!(similarity(&a, 0, &a, 600_000, MAX_OFFSET_MILLIS), None);
+    }
+
+    #[test]
+    fn clusters_group_matching_stations() {
+        let a = noise(1, 100);
+        let items = vec![
+            (String::from("a"), 0, a.clone()),
+            (String::from("b"), 500, a[5..].to_vec()),
+            (String::from("c"), 0, noise(2, 100)),
+            (String::from("d"), 1000, a[10..].to_vec()),
+        ];
+        let clusters = clusters(&items, MAX_OFFSET_MILLIS, 0.9);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].0, vec!["a", "b", "d"]);
+        assert_eq!(clusters[0].1, 1.0);
+    }
+
+    #[test]
+    fn clusters_ignore_captures_of_the_same_station() {
+        let a = noise(1, 100);
+        let items = vec![(String::from("a"), 0, a.clone()), (String::from("a"), 0, a)];
+        assert!(clusters(&items, MAX_OFFSET_MILLIS, 0.9).is_empty());
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let a = noise(3, 20);
+        assert_eq!(from_hex(&to_hex(&a)), a);
+        assert_eq!(to_hex(&[0x00ab, 0xffff]), "00abffff");
+    }
+}
@@ -0,0 +1,281 @@
+use hls_m3u8::{MasterPlaylist, MediaPlaylist, MediaPlaylistOptions};
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+use http::HttpClient;
+use models::HlsItem;
+use models::HlsVariantItem;
+use nowplaying;
+
+/// Playlists larger than this are not parsed
+const MAX_PLAYLIST_BYTES: u64 = 1024 * 1024;
+/// Only the start of a segment is read to see that it is delivered
+const MAX_SEGMENT_BYTES: u64 = 256 * 1024;
+/// Longest wait for the live edge to move
+const MAX_REFRESH_SECONDS: u64 = 15;
+
+/// Validate an hls stream: list the variants of a master playlist, load
+/// the media playlist of the variant with the lowest bandwidth, fetch
+/// its newest segment and reload it to see that the live edge moves.
+pub fn check(client: &HttpClient, url: &str) -> HlsItem {
+    let mut item = HlsItem {
+        status: String::from("ok"),
+        variants: vec![],
+        media_playlist: url.to_string(),
+        target_duration: 0,
+        media_sequence: 0,
+        segments: 0,
+        live: false,
+        edge_moved: false,
+        segment_http_status: 0,
+        now_playing: None,
+        error: String::from(""),
+    };
+    if let Err((status, error)) = validate(client, url, &mut item) {
+        debug!("HLS {} {}: {}", status, url, error);
+        item.status = status.to_string();
+        item.error = error;
+    }
+    item
+}
+
+type Failure = (&'static str, String);
+
+fn validate(client: &HttpClient, url: &str, item: &mut HlsItem) -> Result<(), Failure> {
+    let content = fetch_playlist(client, url)?;
+    if content.contains("#EXT-X-STREAM-INF") {
+        read_master(url, &content, item)?;
+    }
+
+    let media_url = item.media_playlist.clone();
+    let content = if media_url == url { content } else { fetch_playlist(client, &media_url)? };
+    let last_uri = read_media(&media_url, &content, item)?;
+
+    let (status, data) = fetch_segment(client, &last_uri)?;
+    item.segment_http_status = status;
+    item.now_playing = nowplaying::id3_title(&data);
+    match status {
+        200..=299 => {}
+        404 => return Err(("segment_not_found", last_uri)),
+        401 | 403 | 410 => return Err((expired_or_forbidden(&last_uri), format!("http status {} for {}", status, last_uri))),
+        _ => return Err(("segment_error", format!("http status {} for {}", status, last_uri))),
+    }
+
+    if !item.live {
+        return Ok(());
+    }
+    let wait = u64::from(item.target_duration).clamp(1, MAX_REFRESH_SECONDS);
+    thread::sleep(Duration::from_secs(wait + 1));
+    let content = fetch_playlist(client, &media_url)?;
+    let refreshed = parse_media(&content)?;
+    let sequence = refreshed.media_sequence_tag().map(|tag| tag.seq_num()).unwrap_or(0);
+    let refreshed_last = refreshed.segments().last().map(|s| join(&media_url, s.uri()));
+    item.edge_moved = sequence != item.media_sequence || refreshed_last.as_ref() != Some(&last_uri);
+    if !item.edge_moved {
+        return Err(("stale", format!("live edge did not move within {}s", wait + 1)));
+    }
+    Ok(())
+}
+
+/// List the variants of a master playlist, the one with the lowest
+/// bandwidth is checked.
+fn read_master(url: &str, content: &str, item: &mut HlsItem) -> Result<(), Failure> {
+    let master: MasterPlaylist = content
+        .parse()
+        .map_err(|e| ("parse_error", format!("master playlist: {}", e)))?;
+    for tag in master.stream_inf_tags() {
+        item.variants.push(HlsVariantItem {
+            bandwidth: tag.bandwidth(),
+            average_bandwidth: tag.average_bandwidth().unwrap_or(0),
+            codecs: tag.codecs().map(|c| c.as_ref().to_string()).unwrap_or_default(),
+            resolution: tag
+                .resolution()
+                .map(|r| format!("{}x{}", r.width, r.height))
+                .unwrap_or_default(),
+            uri: join(url, tag.uri()),
+        });
+    }
+    item.media_playlist = item
+        .variants
+        .iter()
+        .min_by_key(|variant| variant.bandwidth)
+        .map(|variant| variant.uri.clone())
+        .ok_or_else(|| ("no_variants", String::from("master playlist without variants")))?;
+    Ok(())
+}
+
+/// Read the details of a media playlist, returns the url of the newest
+/// segment.
+fn read_media(media_url: &str, content: &str, item: &mut HlsItem) -> Result<String, Failure> {
+    let media = parse_media(content)?;
+    let segments = media.segments();
+    item.target_duration = media.target_duration_tag().duration().as_secs() as u32;
+    item.media_sequence = media.media_sequence_tag().map(|tag| tag.seq_num()).unwrap_or(0);
+    item.segments = segments.len() as u32;
+    item.live = media.end_list_tag().is_none();
+    let last = segments
+        .last()
+        .ok_or_else(|| ("no_segments", String::from("media playlist without segments")))?;
+    Ok(join(media_url, last.uri()))
+}
+
+fn fetch_playlist(client: &HttpClient, url: &str) -> Result<String, Failure> {
+    let (status, bytes) = client
+        .get(url, |res| {
+            let status = res.status().as_u16();
+            let mut bytes = vec![];
+            if res.status().is_success() {
+                res.take(MAX_PLAYLIST_BYTES)
+                    .read_to_end(&mut bytes)
+                    .map_err(|e| e.to_string())?;
+            }
+            Ok((status, bytes))
+        })
+        .map_err(|e| ("playlist_error", e))?;
+    match status {
+        200..=299 => {}
+        401 | 403 | 410 => return Err((expired_or_forbidden(url), format!("http status {} for {}", status, url))),
+        _ => return Err(("playlist_error", format!("http status {} for {}", status, url))),
+    }
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
+
+/// Http status and start of a segment, the body has to be readable
+fn fetch_segment(client: &HttpClient, url: &str) -> Result<(u32, Vec<u8>), Failure> {
+    let (status, bytes) = client
+        .get(url, |res| {
+            let status = u32::from(res.status().as_u16());
+            let mut bytes = vec![];
+            if res.status().is_success() {
+                res.take(MAX_SEGMENT_BYTES)
+                    .read_to_end(&mut bytes)
+                    .map_err(|e| e.to_string())?;
+            }
+            Ok((status, bytes))
+        })
+        .map_err(|e| ("segment_error", e))?;
+    if (200..=299).contains(&status) && bytes.is_empty() {
+        return Err(("segment_error", format!("empty segment {}", url)));
+    }
+    Ok((status, bytes))
+}
+
+fn parse_media(content: &str) -> Result<MediaPlaylist, Failure> {
+    // many servers round segment durations up or down
+    MediaPlaylistOptions::new()
+        .allowable_excess_segment_duration(Duration::from_secs(30))
+        .parse(content)
+        .map_err(|e| ("parse_error", format!("media playlist: {}", e)))
+}
+
+/// Signed urls (tokens in the query) that are refused have usually expired
+pub fn expired_or_forbidden(url: &str) -> &'static str {
+    match Url::parse(url) {
+        Ok(ref url) if url.query().is_some() => "token_expired",
+        _ => "forbidden",
+    }
+}
+
+/// Resolve a playlist entry against the url of the playlist
+pub fn join(base: &str, uri: &str) -> String {
+    Url::parse(base)
+        .and_then(|base| base.join(uri))
+        .map(|url| url.to_string())
+        .unwrap_or_else(|_| uri.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_item(url: &str) -> HlsItem {
+        HlsItem {
+            status: String::from("ok"),
+            variants: vec![],
+            media_playlist: url.to_string(),
+            target_duration: 0,
+            media_sequence: 0,
+            segments: 0,
+            live: false,
+            edge_moved: false,
+            segment_http_status: 0,
+            now_playing: None,
+            error: String::from(""),
+        }
+    }
+
+    #[test]
+    fn master_playlist_selects_lowest_bandwidth() {
+        let url = "http://example.com/live/master.m3u8";
+        let content = "#EXTM3U\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=128000,CODECS=\"mp4a.40.2\"\n\
+            high/index.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=64000,CODECS=\"mp4a.40.5\"\n\
+            http://cdn.example.com/low/index.m3u8\n";
+        let mut item = empty_item(url);
+        read_master(url, content, &mut item).unwrap();
+        assert_eq!(item.variants.len(), 2);
+        assert_eq!(item.variants[0].uri, "http://example.com/live/high/index.m3u8");
+        assert_eq!(item.variants[0].codecs, "mp4a.40.2");
+        assert_eq!(item.media_playlist, "http://cdn.example.com/low/index.m3u8");
+    }
+
+    #[test]
+    fn master_playlist_without_variants() {
+        let url = "http://example.com/master.m3u8";
+        let mut item = empty_item(url);
+        let content = "#EXTM3U\n#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH=8000,URI=\"iframes.m3u8\"\n";
+        let result = read_master(url, content, &mut item);
+        assert_eq!(result.unwrap_err().0, "no_variants");
+    }
+
+    #[test]
+    fn live_media_playlist() {
+        let url = "http://example.com/live/index.m3u8";
+        let content = "#EXTM3U\n\
+            #EXT-X-TARGETDURATION:10\n\
+            #EXT-X-MEDIA-SEQUENCE:42\n\
+            #EXTINF:10.0,\n\
+            segment42.aac\n\
+            #EXTINF:10.4,\n\
+            segment43.aac?token=abc\n";
+        let mut item = empty_item(url);
+        let last = read_media(url, content, &mut item).unwrap();
+        assert_eq!(last, "http://example.com/live/segment43.aac?token=abc");
+        assert_eq!(item.target_duration, 10);
+        assert_eq!(item.media_sequence, 42);
+        assert_eq!(item.segments, 2);
+        assert!(item.live);
+    }
+
+    #[test]
+    fn ended_media_playlist() {
+        let url = "http://example.com/vod/index.m3u8";
+        let content = "#EXTM3U\n\
+            #EXT-X-TARGETDURATION:6\n\
+            #EXTINF:6.0,\n\
+            a.ts\n\
+            #EXT-X-ENDLIST\n";
+        let mut item = empty_item(url);
+        assert_eq!(read_media(url, content, &mut item).unwrap(), "http://example.com/vod/a.ts");
+        assert!(!item.live);
+    }
+
+    #[test]
+    fn media_playlist_errors() {
+        let url = "http://example.com/index.m3u8";
+        let mut item = empty_item(url);
+        let result = read_media(url, "#EXTM3U\n#EXT-X-TARGETDURATION:6\n", &mut item);
+        assert_eq!(result.unwrap_err().0, "no_segments");
+        let result = read_media(url, "<html></html>", &mut item);
+        assert_eq!(result.unwrap_err().0, "parse_error");
+    }
+
+    #[test]
+    fn refused_urls() {
+        assert_eq!(expired_or_forbidden("http://example.com/a.ts?token=1"), "token_expired");
+        assert_eq!(expired_or_forbidden("http://example.com/a.ts"), "forbidden");
+    }
+}
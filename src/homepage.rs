@@ -0,0 +1,238 @@
+use std::io::Read;
+use std::net::ToSocketAddrs;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+use http::HttpClient;
+use models::HomepageItem;
+use tls;
+
+/// Only the start of a page is read, enough for the title and the
+/// typical texts of parking pages.
+const MAX_PAGE_BYTES: u64 = 256 * 1024;
+
+/// Hosts of domain parking and domain sale services. Expired domains are
+/// often redirected to one of them.
+pub const PARKING_HOSTS: &[&str] = &[
+    "above.com",
+    "afternic.com",
+    "bodis.com",
+    "dan.com",
+    "domainmarket.com",
+    "hugedomains.com",
+    "parkingcrew.net",
+    "sedo.com",
+    "sedoparking.com",
+    "undeveloped.com",
+];
+
+/// Texts found on parked or expired domains, compared in lower case.
+const PARKING_PHRASES: &[&str] = &[
+    "this domain is for sale",
+    "this domain may be for sale",
+    "buy this domain",
+    "domain is parked",
+    "this domain has expired",
+    "this domain name has expired",
+    "parked free",
+    "sedoparking",
+    "parkingcrew",
+];
+
+/// True if the host is one of the parking services or a subdomain of one.
+pub fn is_parking_host(host: &str) -> bool {
+    let host = host.to_lowercase();
+    PARKING_HOSTS
+        .iter()
+        .any(|parking| host == *parking || host.ends_with(&format!(".{}", parking)))
+}
+
+/// Check a station homepage: does the domain resolve, which http status
+/// does it answer with after redirects, is the certificate valid and does
+/// it look like a parked domain.
+pub fn check(client: &HttpClient, homepage: &str) -> HomepageItem {
+    let mut item = HomepageItem {
+        status: String::from("ok"),
+        http_status: 0,
+        final_url: String::from(""),
+        title: String::from(""),
+        resolves: false,
+        tls_valid: None,
+        parked: false,
+        tls: None,
+    };
+    let url = match Url::parse(homepage) {
+        Ok(url) => url,
+        Err(e) => {
+            debug!("Homepage {} is not a valid url: {}", homepage, e);
+            item.status = String::from("invalid_url");
+            return item;
+        }
+    };
+    let host = url.host_str().unwrap_or("").to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    // names of proxied hosts are resolved by the proxy and must not leak to
+    // the local resolver, an answer through the proxy proves they resolve
+    let proxied = client.proxy().map(|proxy| proxy.applies(&host)).unwrap_or(false);
+    if !proxied {
+        item.resolves = resolves(&host, port, Duration::from_secs(client.timeout().into()));
+        if !item.resolves {
+            item.status = String::from("unresolved");
+            return item;
+        }
+    }
+
+    let page = match client.get(homepage, read_page) {
+        Ok(page) => {
+            if page.0.starts_with("https") {
+                item.tls_valid = Some(true);
+            }
+            Ok(page)
+        }
+        Err(e) => {
+            debug!("Homepage {} failed: {}", homepage, e);
+            // retry without certificate checks to tell broken certificates
+            // apart from unreachable servers
+            match client.get_insecure(homepage, read_page) {
+                Ok(page) => {
+                    item.tls_valid = Some(false);
+                    Ok(page)
+                }
+                Err(_) => Err(e),
+            }
+        }
+    };
+    item.resolves = page.is_ok() || item.resolves;
+    let (final_url, http_status, content) = match page {
+        Ok(page) => page,
+        Err(_) => {
+            item.status = String::from("unreachable");
+            return item;
+        }
+    };
+    item.http_status = http_status;
+    item.title = page_title(&content);
+    let final_host = Url::parse(&final_url)
+        .ok()
+        .and_then(|u| u.host_str().map(String::from))
+        .unwrap_or_default();
+    item.tls = tls::inspect(&final_url, client.timeout(), client.proxy());
+    item.final_url = final_url;
+    item.parked = is_parked(&final_host, &content);
+
+    item.status = if item.parked {
+        String::from("parked")
+    } else if http_status >= 400 {
+        String::from("http_error")
+    } else if item.tls_valid == Some(false) {
+        String::from("tls_invalid")
+    } else {
+        String::from("ok")
+    };
+    item
+}
+
+/// Look up a host name, at most for `timeout`. The lookup can block much
+/// longer, it is left running on its own thread then.
+fn resolves(host: &str, port: u16, timeout: Duration) -> bool {
+    let (sender, receiver) = channel();
+    let lookup_host = host.to_string();
+    thread::spawn(move || {
+        let result = (lookup_host.as_str(), port).to_socket_addrs().map(|mut addrs| addrs.next().is_some());
+        let _ = sender.send(result);
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(Ok(found)) => found,
+        Ok(Err(e)) => {
+            debug!("Homepage host {} does not resolve: {}", host, e);
+            false
+        }
+        Err(_) => {
+            debug!("Homepage host {} did not resolve within {:?}", host, timeout);
+            false
+        }
+    }
+}
+
+/// Redirected to a parking service or showing the typical texts
+fn is_parked(final_host: &str, content: &str) -> bool {
+    let content = content.to_lowercase();
+    is_parking_host(final_host) || PARKING_PHRASES.iter().any(|p| content.contains(p))
+}
+
+/// Final url, status and the start of the page content
+fn read_page(res: ::reqwest::Response) -> Result<(String, u32, String), String> {
+    let final_url = res.url().to_string();
+    let status = u32::from(res.status().as_u16());
+    let mut bytes = vec![];
+    res.take(MAX_PAGE_BYTES)
+        .read_to_end(&mut bytes)
+        .map_err(|e| e.to_string())?;
+    Ok((final_url, status, String::from_utf8_lossy(&bytes).to_string()))
+}
+
+fn page_title(content: &str) -> String {
+    let lower = content.to_ascii_lowercase();
+    let start = match lower.find("<title") {
+        Some(start) => start,
+        None => return String::from(""),
+    };
+    let start = match lower[start..].find('>') {
+        Some(offset) => start + offset + 1,
+        None => return String::from(""),
+    };
+    let end = lower[start..].find("</title").map(|e| start + e).unwrap_or(lower.len());
+    content
+        .get(start..end)
+        .unwrap_or("")
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .chars()
+        .take(200)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parking_hosts_and_subdomains() {
+        assert!(is_parking_host("sedo.com"));
+        assert!(is_parking_host("WWW.HugeDomains.com"));
+        assert!(is_parking_host("ww38.parkingcrew.net"));
+        assert!(!is_parking_host("notsedo.com"));
+        assert!(!is_parking_host("sedo.com.example.org"));
+        assert!(!is_parking_host(""));
+    }
+
+    #[test]
+    fn title_is_trimmed_and_collapsed() {
+        assert_eq!(page_title("<html><head><TITLE lang=\"en\">\n  Radio\n  One </TITLE></head>"), "Radio One");
+        assert_eq!(page_title("<title>Unclosed title"), "Unclosed title");
+        assert_eq!(page_title("<html><body>no title</body></html>"), "");
+        assert_eq!(page_title(&format!("<title>{}</title>", "x".repeat(300))).len(), 200);
+    }
+
+    #[test]
+    fn title_keeps_non_ascii_text() {
+        assert_eq!(page_title("<title>Radio Zürich – Live</title>"), "Radio Zürich – Live");
+    }
+
+    #[test]
+    fn lookup_of_addresses_and_invalid_names() {
+        assert!(resolves("127.0.0.1", 80, Duration::from_secs(5)));
+        assert!(!resolves("bad name", 80, Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn parked_by_host_or_phrase() {
+        assert!(is_parked("www.sedo.com", "<html></html>"));
+        assert!(is_parked("radio.example", "<h1>This Domain Is For Sale!</h1>"));
+        assert!(is_parked("radio.example", "<script src=\"//sedoparking.com/frmpark\"></script>"));
+        assert!(!is_parked("radio.example", "<h1>Listen live</h1>"));
+    }
+}
@@ -0,0 +1,161 @@
+use reqwest;
+use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use url::Url;
+
+use config::Config;
+use proxy::OutboundProxy;
+
+/// HTTP client shared by all probes of a task. Connections, TLS sessions
+/// and DNS results are reused across stations, and the number of parallel
+/// requests to the same host is limited, so scanning many stations on one
+/// CDN does not open hundreds of connections to it.
+pub struct HttpClient {
+    client: reqwest::Client,
+    /// same settings, but accepts invalid certificates
+    insecure: reqwest::Client,
+    useragent: String,
+    timeout: u32,
+    max_per_host: usize,
+    proxy: Option<OutboundProxy>,
+    active: Mutex<HashMap<String, usize>>,
+    released: Condvar,
+}
+
+/// A request slot for a host, given back when dropped.
+struct HostSlot<'a> {
+    client: &'a HttpClient,
+    host: String,
+}
+
+impl<'a> Drop for HostSlot<'a> {
+    fn drop(&mut self) {
+        let mut active = self.client.active.lock().unwrap();
+        let remove = match active.get_mut(&self.host) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if remove {
+            active.remove(&self.host);
+        }
+        self.client.released.notify_all();
+    }
+}
+
+impl HttpClient {
+    pub fn new(config: &Config) -> Result<HttpClient, String> {
+        let mut headers = HeaderMap::new();
+        let useragent = HeaderValue::from_str(&config.useragent).map_err(|e| e.to_string())?;
+        headers.insert(USER_AGENT, useragent);
+        let max_per_host = config.http_max_per_host.max(1);
+        let builder = || -> Result<reqwest::ClientBuilder, String> {
+            let builder = reqwest::Client::builder()
+                .default_headers(headers.clone())
+                .timeout(Duration::from_secs(config.tcp_timeout.into()))
+                .max_idle_per_host(max_per_host);
+            match config.outbound_proxy {
+                Some(ref proxy) => Ok(builder.proxy(proxy.reqwest()?)),
+                None => Ok(builder),
+            }
+        };
+        let client = builder()?.build().map_err(|e| e.to_string())?;
+        let insecure = builder()?
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(HttpClient {
+            client,
+            insecure,
+            useragent: config.useragent.clone(),
+            timeout: config.tcp_timeout,
+            max_per_host,
+            proxy: config.outbound_proxy.clone(),
+            active: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        })
+    }
+
+    /// True if the client was built from the same settings as `config`,
+    /// otherwise it has to be replaced after a config reload.
+    pub fn is_current(&self, config: &Config) -> bool {
+        self.useragent == config.useragent
+            && self.timeout == config.tcp_timeout
+            && self.max_per_host == config.http_max_per_host.max(1)
+            && self.proxy == config.outbound_proxy
+    }
+
+    pub fn timeout(&self) -> u32 {
+        self.timeout
+    }
+
+    pub fn useragent(&self) -> &str {
+        &self.useragent
+    }
+
+    /// The proxy requests go through, also for connections not made by
+    /// this client
+    pub fn proxy(&self) -> Option<&OutboundProxy> {
+        self.proxy.as_ref()
+    }
+
+    /// Send a GET request and handle the response with `f`. The host slot
+    /// is held until `f` returns, so reading the body counts as well. The
+    /// limit applies to the host of `url`, not to redirect targets.
+    pub fn get<T, F>(&self, url: &str, f: F) -> Result<T, String>
+    where
+        F: FnOnce(reqwest::Response) -> Result<T, String>,
+    {
+        self.request(&self.client, url, None, f)
+    }
+
+    /// Like `get`, but sent with another user agent
+    pub fn get_as<T, F>(&self, url: &str, useragent: &str, f: F) -> Result<T, String>
+    where
+        F: FnOnce(reqwest::Response) -> Result<T, String>,
+    {
+        self.request(&self.client, url, Some(useragent), f)
+    }
+
+    /// Like `get`, but does not verify certificates. Only for finding out
+    /// if a failed https request failed because of the certificate.
+    pub fn get_insecure<T, F>(&self, url: &str, f: F) -> Result<T, String>
+    where
+        F: FnOnce(reqwest::Response) -> Result<T, String>,
+    {
+        self.request(&self.insecure, url, None, f)
+    }
+
+    fn request<T, F>(&self, client: &reqwest::Client, url: &str, useragent: Option<&str>, f: F) -> Result<T, String>
+    where
+        F: FnOnce(reqwest::Response) -> Result<T, String>,
+    {
+        let host = Url::parse(url)
+            .map_err(|e| e.to_string())?
+            .host_str()
+            .unwrap_or("")
+            .to_lowercase();
+        let _slot = self.acquire(host);
+        let mut request = client.get(url);
+        if let Some(useragent) = useragent {
+            request = request.header(USER_AGENT, useragent);
+        }
+        let response = request.send().map_err(|e| e.to_string())?;
+        f(response)
+    }
+
+    fn acquire<'a>(&'a self, host: String) -> HostSlot<'a> {
+        let mut active = self.active.lock().unwrap();
+        while active.get(&host).cloned().unwrap_or(0) >= self.max_per_host {
+            trace!("Wait for free connection to {}", host);
+            active = self.released.wait(active).unwrap();
+        }
+        *active.entry(host.clone()).or_insert(0) += 1;
+        HostSlot { client: self, host }
+    }
+}
@@ -0,0 +1,131 @@
+use colored;
+use env_logger;
+use env_logger::fmt::Target;
+use log::{LevelFilter, Record};
+use serde_json;
+
+use std::cell::RefCell;
+use std::env;
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl ::std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', use text or json", s)),
+        }
+    }
+}
+
+/// Fields of the station that is checked by the current thread. They are
+/// added to every log line written from this thread.
+#[derive(Clone, Debug)]
+struct StationFields {
+    uuid: String,
+    source: String,
+    url: String,
+}
+
+thread_local! {
+    static STATION: RefCell<Option<StationFields>> = const { RefCell::new(None) };
+}
+
+/// Guard returned by `station_context`, removes the station fields from
+/// the log lines of the thread when dropped.
+pub struct StationContext {
+    _private: (),
+}
+
+impl Drop for StationContext {
+    fn drop(&mut self) {
+        STATION.with(|station| {
+            *station.borrow_mut() = None;
+        });
+    }
+}
+
+/// Attach the station to all log lines of the current thread until the
+/// returned guard is dropped.
+pub fn station_context(uuid: &str, source: &str, url: &str) -> StationContext {
+    STATION.with(|station| {
+        *station.borrow_mut() = Some(StationFields {
+            uuid: String::from(uuid),
+            source: String::from(source),
+            url: String::from(url),
+        });
+    });
+    StationContext { _private: () }
+}
+
+fn current_station() -> Option<StationFields> {
+    STATION.with(|station| station.borrow().clone())
+}
+
+/// Verbosity 0 logs info and above, -v adds debug, -vv adds trace.
+/// RUST_LOG overrides the levels if it is set.
+pub fn init(verbosity: u8, format: LogFormat) {
+    let level = match verbosity {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let mut builder = env_logger::Builder::new();
+    builder
+        .target(Target::Stdout)
+        .filter_level(LevelFilter::Warn)
+        .filter_module("stream_check_rust", level);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    match format {
+        LogFormat::Text => {
+            builder.format(|buf, record| writeln!(buf, "{}", format_text(record)));
+        }
+        LogFormat::Json => {
+            colored::control::set_override(false);
+            builder.format(|buf, record| {
+                let timestamp = buf.timestamp();
+                writeln!(buf, "{}", format_json(record, &timestamp.to_string()))
+            });
+        }
+    }
+    builder.init();
+}
+
+fn format_text(record: &Record) -> String {
+    match current_station() {
+        Some(station) => format!(
+            "{:<5} [uuid={} source={} url={}] {}",
+            record.level(),
+            station.uuid,
+            station.source,
+            station.url,
+            record.args()
+        ),
+        None => format!("{:<5} {}", record.level(), record.args()),
+    }
+}
+
+fn format_json(record: &Record, timestamp: &str) -> String {
+    let mut line = json!({
+        "timestamp": timestamp,
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    if let Some(station) = current_station() {
+        line["station_uuid"] = json!(station.uuid);
+        line["source"] = json!(station.source);
+        line["url"] = json!(station.url);
+    }
+    serde_json::to_string(&line).unwrap_or_default()
+}
@@ -0,0 +1,325 @@
+use db::DbError;
+use mysql;
+
+/// A schema change. DDL statements commit implicitly in mysql, so a
+/// migration can not be applied and recorded in one transaction. Each one
+/// has to be safe to run again if recording it failed.
+enum Migration {
+    /// A statement that can simply be repeated, e.g. CREATE TABLE IF NOT
+    /// EXISTS or a single ALTER TABLE that replaces a key
+    Query(&'static str),
+    /// Add columns (name, definition) to a table, columns that already
+    /// exist are skipped
+    AddColumns(&'static str, &'static [(&'static str, &'static str)]),
+}
+
+/// Schema changes needed by stream-check on top of the radiobrowser
+/// schema. Each migration is applied once, in order, and recorded in the
+/// table StreamCheckMigrations. Never change an applied migration, add a
+/// new one instead.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    (
+        "001_favicon_history",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationFaviconHistory(
+            Id INT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            OldFavicon TEXT NOT NULL,
+            NewFavicon TEXT NOT NULL,
+            Width INT,
+            Height INT,
+            ChangeTime DATETIME NOT NULL,
+            INDEX(StationUuid)
+        )"),
+    ),
+    (
+        "002_station_favicon",
+        Migration::AddColumns("Station", &[("FaviconWidth", "INT"), ("FaviconHeight", "INT"), ("FaviconPinned", "BOOL NOT NULL DEFAULT 0")]),
+    ),
+    (
+        "003_station_favicon_mirror",
+        Migration::AddColumns("Station", &[("FaviconMirror", "VARCHAR(100)")]),
+    ),
+    (
+        "004_station_last_favicon_check",
+        Migration::AddColumns("Station", &[("LastFaviconCheckTime", "DATETIME")]),
+    ),
+    (
+        "005_homepage_check",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationHomepageCheck(
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            Status VARCHAR(20) NOT NULL,
+            HttpStatus INT NOT NULL,
+            FinalUrl TEXT NOT NULL,
+            Title VARCHAR(200) NOT NULL,
+            Resolves BOOL NOT NULL,
+            TlsValid BOOL,
+            Parked BOOL NOT NULL,
+            CheckTime DATETIME NOT NULL,
+            PRIMARY KEY(StationUuid,Source),
+            INDEX(Status)
+        )"),
+    ),
+    (
+        "006_station_suspicious",
+        Migration::AddColumns("Station", &[("Suspicious", "VARCHAR(255)")]),
+    ),
+    (
+        "007_tls_check",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationTlsCheck(
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            Target VARCHAR(10) NOT NULL,
+            Host VARCHAR(255) NOT NULL,
+            Status VARCHAR(20) NOT NULL,
+            Issuer VARCHAR(255) NOT NULL,
+            Subject VARCHAR(255) NOT NULL,
+            NotAfter DATETIME,
+            HostnameMatch BOOL NOT NULL,
+            ChainValid BOOL NOT NULL,
+            Error TEXT NOT NULL,
+            CheckTime DATETIME NOT NULL,
+            PRIMARY KEY(StationUuid,Source,Target),
+            INDEX(NotAfter)
+        )"),
+    ),
+    (
+        "008_https_upgrade",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationHttpsUpgrade(
+            StationUuid CHAR(36) NOT NULL PRIMARY KEY,
+            Source VARCHAR(100) NOT NULL,
+            OldUrl TEXT NOT NULL,
+            NewUrl TEXT NOT NULL,
+            Applied BOOL NOT NULL,
+            CheckTime DATETIME NOT NULL
+        )"),
+    ),
+    (
+        "009_check_loudness",
+        Migration::AddColumns("StationCheck", &[("LoudnessRms", "FLOAT"), ("LoudnessPeak", "FLOAT"), ("SilenceSeconds", "FLOAT"), ("Silent", "BOOL")]),
+    ),
+    (
+        "010_check_history_loudness",
+        Migration::AddColumns("StationCheckHistory", &[("LoudnessRms", "FLOAT"), ("LoudnessPeak", "FLOAT"), ("SilenceSeconds", "FLOAT"), ("Silent", "BOOL")]),
+    ),
+    (
+        "011_fingerprint",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationFingerprint(
+            Id INT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            CaptureMillis BIGINT NOT NULL,
+            Fingerprint TEXT NOT NULL,
+            INDEX (CaptureMillis)
+        )"),
+    ),
+    (
+        "012_check_bitrate_measured",
+        Migration::AddColumns("StationCheck", &[("BitrateMeasured", "INT"), ("BitrateMismatch", "BOOL")]),
+    ),
+    (
+        "013_check_history_bitrate_measured",
+        Migration::AddColumns("StationCheckHistory", &[("BitrateMeasured", "INT"), ("BitrateMismatch", "BOOL")]),
+    ),
+    (
+        "014_soak_check",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationSoakCheck(
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            ListenSeconds FLOAT NOT NULL,
+            Underruns INT NOT NULL,
+            StalledSeconds FLOAT NOT NULL,
+            Disconnects INT NOT NULL,
+            Reconnects INT NOT NULL,
+            FailedReconnects INT NOT NULL,
+            Score INT NOT NULL,
+            Error TEXT NOT NULL,
+            CheckTime DATETIME NOT NULL,
+            PRIMARY KEY(StationUuid,Source),
+            INDEX(Score)
+        )"),
+    ),
+    (
+        "015_hls_check",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationHlsCheck(
+            CheckUuid CHAR(36) NOT NULL PRIMARY KEY,
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            Status VARCHAR(20) NOT NULL,
+            MediaPlaylist TEXT NOT NULL,
+            TargetDuration INT NOT NULL,
+            MediaSequence BIGINT NOT NULL,
+            Segments INT NOT NULL,
+            Live BOOL NOT NULL,
+            EdgeMoved BOOL NOT NULL,
+            SegmentHttpStatus INT NOT NULL,
+            Error TEXT NOT NULL,
+            CheckTime DATETIME NOT NULL,
+            INDEX(StationUuid),
+            INDEX(CheckTime)
+        )"),
+    ),
+    (
+        "016_hls_variant",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationHlsVariant(
+            Id INT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+            CheckUuid CHAR(36) NOT NULL,
+            Bandwidth BIGINT NOT NULL,
+            AverageBandwidth BIGINT NOT NULL,
+            Codecs VARCHAR(255) NOT NULL,
+            Resolution VARCHAR(20) NOT NULL,
+            Uri TEXT NOT NULL,
+            INDEX(CheckUuid)
+        )"),
+    ),
+    (
+        "017_check_dash",
+        Migration::AddColumns("StationCheck", &[("Dash", "BOOL NOT NULL DEFAULT 0")]),
+    ),
+    (
+        "018_check_history_dash",
+        Migration::AddColumns("StationCheckHistory", &[("Dash", "BOOL NOT NULL DEFAULT 0")]),
+    ),
+    (
+        "019_dash_check",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationDashCheck(
+            CheckUuid CHAR(36) NOT NULL PRIMARY KEY,
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            Status VARCHAR(20) NOT NULL,
+            Live BOOL NOT NULL,
+            SegmentUrl TEXT NOT NULL,
+            SegmentHttpStatus INT NOT NULL,
+            Error TEXT NOT NULL,
+            CheckTime DATETIME NOT NULL,
+            INDEX(StationUuid),
+            INDEX(CheckTime)
+        )"),
+    ),
+    (
+        "020_dash_representation",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationDashRepresentation(
+            Id INT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+            CheckUuid CHAR(36) NOT NULL,
+            RepresentationId VARCHAR(100) NOT NULL,
+            ContentType VARCHAR(100) NOT NULL,
+            Codecs VARCHAR(255) NOT NULL,
+            Bandwidth BIGINT NOT NULL,
+            INDEX(CheckUuid)
+        )"),
+    ),
+    (
+        "021_now_playing",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationNowPlaying(
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            MetadataType VARCHAR(10) NOT NULL,
+            HasMetadata BOOL NOT NULL,
+            Title VARCHAR(512) NOT NULL,
+            TitleTime DATETIME,
+            CheckTime DATETIME NOT NULL,
+            PRIMARY KEY(StationUuid,Source)
+        )"),
+    ),
+    (
+        "022_server_status",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationServerStatus(
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            ServerType VARCHAR(20) NOT NULL,
+            Version VARCHAR(100) NOT NULL,
+            StatusUrl TEXT NOT NULL,
+            Listeners INT,
+            PeakListeners INT,
+            Description VARCHAR(512) NOT NULL,
+            Mount VARCHAR(255) NOT NULL,
+            CheckTime DATETIME NOT NULL,
+            PRIMARY KEY(StationUuid,Source)
+        )"),
+    ),
+    (
+        "023_server_mount",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationServerMount(
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            Mount VARCHAR(255) NOT NULL,
+            Url TEXT NOT NULL,
+            ContentType VARCHAR(100) NOT NULL,
+            Bitrate INT NOT NULL,
+            Listeners INT,
+            INDEX(StationUuid,Source)
+        )"),
+    ),
+    (
+        "024_region_check",
+        Migration::Query("CREATE TABLE IF NOT EXISTS StationRegionCheck(
+            CheckUuid CHAR(36) NOT NULL,
+            StationUuid CHAR(36) NOT NULL,
+            Source VARCHAR(100) NOT NULL,
+            Region VARCHAR(50) NOT NULL,
+            CheckOK BOOL NOT NULL,
+            HttpStatus INT NOT NULL,
+            Error TEXT NOT NULL,
+            CheckTime DATETIME NOT NULL,
+            PRIMARY KEY(CheckUuid,Region),
+            INDEX(StationUuid,Source)
+        )"),
+    ),
+    (
+        "025_station_geo_restricted",
+        Migration::AddColumns("Station", &[("GeoRestricted", "BOOL")]),
+    ),
+];
+
+pub fn run(pool: &mysql::Pool) -> Result<(), DbError> {
+    pool.prep_exec(
+        "CREATE TABLE IF NOT EXISTS StreamCheckMigrations(
+            Name VARCHAR(100) NOT NULL PRIMARY KEY,
+            Applied DATETIME NOT NULL
+        )",
+        (),
+    )?;
+    for &(name, ref migration) in MIGRATIONS {
+        let applied: Option<String> =
+            pool.first_exec("SELECT Name FROM StreamCheckMigrations WHERE Name=?", (name,))?
+                .map(mysql::from_row);
+        if applied.is_some() {
+            continue;
+        }
+        info!("Apply migration {}", name);
+        match *migration {
+            Migration::Query(query) => {
+                pool.prep_exec(query, ())?;
+            }
+            Migration::AddColumns(table, columns) => {
+                let mut missing = vec![];
+                for &(column, definition) in columns {
+                    if column_exists(pool, table, column)? {
+                        info!("Column {}.{} already exists", table, column);
+                    } else {
+                        missing.push(format!("ADD COLUMN {} {}", column, definition));
+                    }
+                }
+                if !missing.is_empty() {
+                    pool.prep_exec(format!("ALTER TABLE {} {}", table, missing.join(", ")), ())?;
+                }
+            }
+        }
+        pool.prep_exec(
+            "INSERT INTO StreamCheckMigrations(Name,Applied) VALUES(?,NOW())",
+            (name,),
+        )?;
+    }
+    Ok(())
+}
+
+fn column_exists(pool: &mysql::Pool, table: &str, column: &str) -> Result<bool, DbError> {
+    let count: Option<u64> = pool
+        .first_exec(
+            "SELECT COUNT(*) FROM information_schema.COLUMNS WHERE TABLE_SCHEMA=DATABASE() AND TABLE_NAME=? AND COLUMN_NAME=?",
+            (table, column),
+        )?
+        .map(mysql::from_row);
+    Ok(count.unwrap_or(0) > 0)
+}
@@ -0,0 +1,186 @@
+use image;
+use image::imageops;
+use image::{FilterType, GenericImageView, RgbaImage};
+use sha2::{Digest, Sha256};
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Edge lengths of the square png thumbnails created for every icon
+pub const THUMBNAIL_SIZES: &[u32] = &[32, 64, 128];
+
+/// Unreferenced icons younger than this are not removed, the station
+/// referencing them may not be written to the database yet.
+const GC_GRACE_PERIOD: Duration = Duration::from_secs(24 * 3600);
+
+/// Store png thumbnails of an icon in the mirror directory. The icons are
+/// content addressed by the sha256 of the original file:
+/// `<dir>/<hash[0..2]>/<hash>/<size>.png`
+///
+/// Returns the mirror path `<hash[0..2]>/<hash>` relative to `dir`.
+pub fn store(dir: &str, bytes: &[u8]) -> Result<String, String> {
+    let hash = format!("{:x}", Sha256::digest(bytes));
+    let mirror_path = format!("{}/{}", &hash[0..2], hash);
+    let target = Path::new(dir).join(&mirror_path);
+    if target.is_dir() {
+        return Ok(mirror_path);
+    }
+
+    let img = image::load_from_memory(bytes).map_err(|e| e.to_string())?;
+    let parent = Path::new(dir).join(&hash[0..2]);
+    let tmp = parent.join(format!(".{}.tmp", hash));
+    fs::create_dir_all(&tmp).map_err(|e| e.to_string())?;
+    for &size in THUMBNAIL_SIZES {
+        let thumbnail = thumbnail(&img, size);
+        thumbnail
+            .save(tmp.join(format!("{}.png", size)))
+            .map_err(|e| e.to_string())?;
+    }
+    // publish all sizes at once
+    if let Err(e) = fs::rename(&tmp, &target) {
+        let _ = fs::remove_dir_all(&tmp);
+        if !target.is_dir() {
+            return Err(e.to_string());
+        }
+    }
+    Ok(mirror_path)
+}
+
+/// Scale the image to fit into a size x size square, centered on a
+/// transparent background.
+fn thumbnail(img: &image::DynamicImage, size: u32) -> RgbaImage {
+    let scaled = img.resize(size, size, FilterType::Lanczos3);
+    let (width, height) = scaled.dimensions();
+    let mut canvas = RgbaImage::new(size, size);
+    imageops::overlay(&mut canvas, &scaled.to_rgba(), (size - width) / 2, (size - height) / 2);
+    canvas
+}
+
+/// Remove mirrored icons that are not referenced by any station anymore.
+/// Returns the number of removed icons.
+pub fn collect_garbage(dir: &str, referenced: &HashSet<String>) -> io::Result<usize> {
+    remove_unreferenced(dir, referenced, GC_GRACE_PERIOD)
+}
+
+fn remove_unreferenced(dir: &str, referenced: &HashSet<String>, grace_period: Duration) -> io::Result<usize> {
+    let mut removed = 0;
+    let now = SystemTime::now();
+    for prefix in fs::read_dir(dir)? {
+        let prefix = prefix?;
+        if !prefix.file_type()?.is_dir() {
+            continue;
+        }
+        let prefix_name = prefix.file_name().to_string_lossy().to_string();
+        for entry in fs::read_dir(prefix.path())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let mirror_path = format!("{}/{}", prefix_name, name);
+            if referenced.contains(&mirror_path) {
+                continue;
+            }
+            let age = entry
+                .metadata()?
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .unwrap_or_else(|| Duration::from_secs(0));
+            if age < grace_period {
+                continue;
+            }
+            debug!("Remove unused favicon mirror {}", mirror_path);
+            fs::remove_dir_all(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Directory below the system temp dir, removed at the end of the test
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = env::temp_dir().join(format!("mirror-test-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let img = image::DynamicImage::ImageRgba8(RgbaImage::new(width, height));
+        let mut bytes = vec![];
+        img.write_to(&mut bytes, image::ImageOutputFormat::PNG).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn store_is_content_addressed() {
+        let dir = TempDir::new("store");
+        let icon = png(16, 16);
+        let path = store(dir.path(), &icon).unwrap();
+        let hash = format!("{:x}", Sha256::digest(&icon));
+        assert_eq!(path, format!("{}/{}", &hash[0..2], hash));
+        for &size in THUMBNAIL_SIZES {
+            let thumbnail = image::open(dir.0.join(&path).join(format!("{}.png", size))).unwrap();
+            assert_eq!(thumbnail.dimensions(), (size, size));
+        }
+
+        let other = store(dir.path(), &png(20, 10)).unwrap();
+        assert_ne!(other, path);
+    }
+
+    #[test]
+    fn store_again_is_a_no_op() {
+        let dir = TempDir::new("store-again");
+        let icon = png(16, 16);
+        let path = store(dir.path(), &icon).unwrap();
+        let thumbnail = dir.0.join(&path).join("32.png");
+        let modified = fs::metadata(&thumbnail).unwrap().modified().unwrap();
+
+        assert_eq!(store(dir.path(), &icon).unwrap(), path);
+        assert_eq!(fs::metadata(&thumbnail).unwrap().modified().unwrap(), modified);
+        assert_eq!(fs::read_dir(dir.0.join(&path[0..2])).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn collect_garbage_keeps_referenced_icons() {
+        let dir = TempDir::new("gc-referenced");
+        let used = store(dir.path(), &png(16, 16)).unwrap();
+        let unused = store(dir.path(), &png(8, 8)).unwrap();
+        let referenced: HashSet<String> = vec![used.clone()].into_iter().collect();
+
+        assert_eq!(remove_unreferenced(dir.path(), &referenced, Duration::from_secs(0)).unwrap(), 1);
+        assert!(dir.0.join(&used).is_dir());
+        assert!(!dir.0.join(&unused).exists());
+    }
+
+    #[test]
+    fn collect_garbage_keeps_new_icons() {
+        let dir = TempDir::new("gc-grace");
+        let unused = store(dir.path(), &png(16, 16)).unwrap();
+
+        assert_eq!(collect_garbage(dir.path(), &HashSet::new()).unwrap(), 0);
+        assert!(dir.0.join(&unused).is_dir());
+    }
+}
@@ -10,6 +10,7 @@ pub struct StationItem {
     pub check_ok: bool,
     pub urlcache: String,
     pub favicon: String,
+    pub favicon_mirror: String,
     pub homepage: String,
 }
 
@@ -26,13 +27,332 @@ pub struct StationCheckItem {
     pub check_time: String,
 }
 
-#[derive(Clone,Debug)]
+#[derive(Clone,Debug,Default)]
 pub struct StationCheckItemNew {
     pub station_uuid: String,
     pub source: String,
     pub codec: String,
     pub bitrate: i32,
     pub hls: bool,
+    pub dash: bool,
+    pub check_ok: bool,
+    pub url: String,
+    /// reason if the stream works but looks hijacked, check_ok is false then
+    pub suspicious: Option<String>,
+    pub tls: Option<TlsItem>,
+    pub https_upgrade: Option<HttpsUpgradeItem>,
+    pub audio: Option<AudioItem>,
+    pub fingerprint: Option<FingerprintItem>,
+    pub measured_bitrate: Option<BitrateItem>,
+    pub hls_details: Option<HlsItem>,
+    pub dash_details: Option<DashItem>,
+    pub now_playing: Option<NowPlayingItem>,
+    pub server_status: Option<ServerStatusItem>,
+    /// results of the checks through the region proxies
+    pub regions: Vec<RegionCheckItem>,
+    /// works from some vantage points only, None without regions
+    pub geo_restricted: Option<bool>,
+}
+
+#[derive(Clone,Debug)]
+pub struct StationFaviconCheckItem {
+    pub station_uuid: String,
+    pub source: String,
+    pub favicon_change: Option<FaviconChange>,
+    pub favicon_mirror: Option<String>,
+    /// width and height of the unchanged favicon, the file behind the url may change
+    pub favicon_size: Option<(u32, u32)>,
+}
+
+#[derive(Clone,Debug)]
+pub struct FaviconItem {
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+    pub format: String,
+    /// path in the local favicon mirror, empty if not mirrored
+    pub mirror: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct FaviconChange {
+    pub old: String,
+    pub new: FaviconItem,
+}
+
+#[derive(Clone,Debug)]
+pub struct FaviconHistoryItem {
+    pub id: i32,
+    pub station_uuid: String,
+    pub source: String,
+    pub old_favicon: String,
+    pub new_favicon: String,
+    pub width: i32,
+    pub height: i32,
+    pub change_time: String,
+}
+
+/// Outcome of reverting a favicon change
+#[derive(Clone,Debug)]
+pub enum FaviconRevert {
+    Reverted(FaviconHistoryItem),
+    NotFound,
+    /// the favicon was changed again, it is the contained one now
+    Changed(String),
+}
+
+#[derive(Clone,Debug)]
+pub struct HomepageItem {
+    /// ok, invalid_url, unresolved, unreachable, http_error, tls_invalid or parked
+    pub status: String,
+    /// 0 if there was no http response
+    pub http_status: u32,
+    pub final_url: String,
+    pub title: String,
+    pub resolves: bool,
+    /// None for plain http homepages
+    pub tls_valid: Option<bool>,
+    pub parked: bool,
+    pub tls: Option<TlsItem>,
+}
+
+#[derive(Clone,Debug)]
+pub struct StationHomepageCheckItem {
+    pub station_uuid: String,
+    pub source: String,
+    pub homepage: HomepageItem,
+}
+
+#[derive(Clone,Debug)]
+pub struct HomepageReportItem {
+    pub station_uuid: String,
+    pub name: String,
+    pub homepage: String,
+    pub status: String,
+    pub http_status: i32,
+    pub final_url: String,
+    pub title: String,
+    pub check_time: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct SuspiciousReportItem {
+    pub station_uuid: String,
+    pub name: String,
+    pub url: String,
+    pub urlcache: String,
+    pub reason: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct TlsItem {
+    pub host: String,
+    /// valid, expired, insecure_only or handshake_failed
+    pub status: String,
+    pub issuer: String,
+    pub subject: String,
+    pub not_after: String,
+    /// negative if expired, 0 without certificate
+    pub expires_in_secs: i64,
+    pub hostname_match: bool,
+    pub chain_valid: bool,
+    /// verification or handshake errors
+    pub error: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct TlsReportItem {
+    pub station_uuid: String,
+    pub name: String,
+    pub target: String,
+    pub host: String,
+    pub status: String,
+    pub issuer: String,
+    pub not_after: String,
+    pub error: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct HttpsUpgradeItem {
+    pub old_url: String,
+    pub new_url: String,
+    /// true if the station url was replaced
+    pub applied: bool,
+}
+
+#[derive(Clone,Debug)]
+pub struct HttpsUpgradeReportItem {
+    pub station_uuid: String,
+    pub name: String,
+    pub old_url: String,
+    pub new_url: String,
+    pub applied: bool,
+    pub check_time: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct AudioItem {
+    pub rms_db: f32,
+    pub peak_db: f32,
+    /// longest silence in the decoded audio
+    pub silence_secs: f32,
+    pub decoded_secs: f32,
+    pub silent: bool,
+}
+
+#[derive(Clone,Debug)]
+pub struct HlsItem {
+    /// ok, playlist_error, parse_error, no_variants, no_segments, stale,
+    /// segment_not_found, segment_error, token_expired or forbidden
+    pub status: String,
+    /// empty if the stream url is a media playlist
+    pub variants: Vec<HlsVariantItem>,
+    /// the checked media playlist
+    pub media_playlist: String,
+    pub target_duration: u32,
+    pub media_sequence: u64,
+    pub segments: u32,
+    /// no EXT-X-ENDLIST
+    pub live: bool,
+    pub edge_moved: bool,
+    pub segment_http_status: u32,
+    /// title from id3 tags of the segment
+    pub now_playing: Option<String>,
+    pub error: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct HlsVariantItem {
+    pub bandwidth: u64,
+    pub average_bandwidth: u64,
+    pub codecs: String,
+    pub resolution: String,
+    pub uri: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct DashItem {
+    /// ok, manifest_error, parse_error, no_representations,
+    /// segment_not_found, segment_error, token_expired or forbidden
+    pub status: String,
+    /// type="dynamic"
+    pub live: bool,
+    pub representations: Vec<DashRepresentationItem>,
+    /// the last fetched segment
+    pub segment_url: String,
+    pub segment_http_status: u32,
+    pub error: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct DashRepresentationItem {
+    pub id: String,
+    /// mime type, e.g. audio/mp4
+    pub content_type: String,
+    pub codecs: String,
+    pub bandwidth: u64,
+}
+
+#[derive(Clone,Debug)]
+pub struct NowPlayingItem {
+    /// icy or id3, empty if the stream has no metadata
+    pub metadata_type: String,
+    /// a title was found in the metadata
+    pub has_metadata: bool,
+    /// last title seen, empty if none
+    pub title: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct RegionCheckItem {
+    pub region: String,
     pub check_ok: bool,
+    /// 0 if there was no response
+    pub http_status: u32,
+    pub error: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct ServerStatusItem {
+    /// icecast or shoutcast
+    pub server_type: String,
+    /// from the status page, else the Server header
+    pub version: String,
+    /// the status page that answered
+    pub status_url: String,
+    pub listeners: Option<u32>,
+    pub peak_listeners: Option<u32>,
+    pub description: String,
+    /// mount of the stream on the server
+    pub mount: String,
+    /// other mounts of the same server, often other bitrates or codecs
+    pub siblings: Vec<ServerMountItem>,
+}
+
+#[derive(Clone,Debug)]
+pub struct ServerMountItem {
+    pub mount: String,
     pub url: String,
-}
\ No newline at end of file
+    pub content_type: String,
+    /// kbit/s, 0 if unknown
+    pub bitrate: u32,
+    pub listeners: Option<u32>,
+}
+
+#[derive(Clone,Debug)]
+pub struct BitrateItem {
+    /// kbit/s the stream was sent with, while bitrate is what it claims
+    pub measured: i32,
+    pub mismatch: bool,
+}
+
+#[derive(Clone,Debug)]
+pub struct FingerprintItem {
+    /// start of the capture, unix time in milliseconds
+    pub capture_millis: i64,
+    /// hex encoded, see fingerprint::to_hex
+    pub fingerprint: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct FingerprintReportItem {
+    pub station_uuid: String,
+    pub name: String,
+    pub url: String,
+    pub capture_millis: i64,
+    pub fingerprint: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct SoakItem {
+    pub listen_secs: f32,
+    /// gaps without data while connected
+    pub underruns: u32,
+    /// time without data, including the time without connection
+    pub stalled_secs: f32,
+    pub disconnects: u32,
+    pub reconnects: u32,
+    pub failed_reconnects: u32,
+    /// stability from 0 (unusable) to 100
+    pub score: u32,
+    pub error: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct StationSoakCheckItem {
+    pub station_uuid: String,
+    pub source: String,
+    pub soak: SoakItem,
+}
+
+#[derive(Clone,Debug)]
+pub struct SoakReportItem {
+    pub station_uuid: String,
+    pub name: String,
+    pub url: String,
+    pub score: u32,
+    pub underruns: u32,
+    pub disconnects: u32,
+    pub failed_reconnects: u32,
+    pub check_time: String,
+}
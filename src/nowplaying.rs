@@ -0,0 +1,198 @@
+/// Title from an icy metadata block like
+/// `StreamTitle='Artist - Title';StreamUrl='';`, None if it is empty.
+pub fn icy_title(metadata: &str) -> Option<String> {
+    let start = metadata.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &metadata[start..];
+    // titles may contain quotes, the value ends with the field separator
+    let end = rest.find("';").unwrap_or_else(|| rest.trim_end_matches('\'').len());
+    let title = rest[..end].trim();
+    if title.is_empty() {
+        None
+    } else {
+        Some(title.to_string())
+    }
+}
+
+/// Title of the last ID3v2 tag in a segment, as "artist - title" if both
+/// are set. Packed audio segments start with a tag, in transport streams
+/// the tags are found as long as they fit into one packet.
+pub fn id3_title(data: &[u8]) -> Option<String> {
+    let mut title = None;
+    let mut position = 0;
+    while let Some(offset) = find(&data[position..], b"ID3") {
+        let start = position + offset;
+        if let Some((found, size)) = parse_id3(&data[start..]) {
+            if found.is_some() {
+                title = found;
+            }
+            // the tag may be cut off at the end of the segment
+            position = (start + size).min(data.len());
+        } else {
+            position = start + 3;
+        }
+    }
+    title
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Title and length of the tag at the start of `data`
+fn parse_id3(data: &[u8]) -> Option<(Option<String>, usize)> {
+    if data.len() < 10 || data[3] < 2 || data[3] > 4 || data[4] == 0xff {
+        return None;
+    }
+    let version = data[3];
+    let size = syncsafe(&data[6..10])?;
+    let end = (10 + size).min(data.len());
+    let mut title = String::from("");
+    let mut artist = String::from("");
+    let mut position = 10;
+    // id3v2.2 uses 3 byte frame ids and sizes
+    let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+    while position + header_len <= end {
+        let id = &data[position..position + id_len];
+        if id[0] == 0 {
+            break;
+        }
+        let size_bytes = &data[position + id_len..position + 2 * id_len];
+        let frame_size = match version {
+            2 | 3 => size_bytes.iter().fold(0usize, |size, b| size << 8 | usize::from(*b)),
+            _ => syncsafe(size_bytes)?,
+        };
+        let content_start = position + header_len;
+        let content_end = (content_start + frame_size).min(end);
+        let content = &data[content_start..content_end];
+        match id {
+            b"TIT2" | b"TT2" => title = decode_text(content),
+            b"TPE1" | b"TP1" => artist = decode_text(content),
+            _ => {}
+        }
+        position = content_start + frame_size;
+    }
+    let found = match (artist.is_empty(), title.is_empty()) {
+        (_, true) => None,
+        (true, false) => Some(title),
+        (false, false) => Some(format!("{} - {}", artist, title)),
+    };
+    Some((found, 10 + size))
+}
+
+fn syncsafe(bytes: &[u8]) -> Option<usize> {
+    if bytes.iter().any(|b| b & 0x80 != 0) {
+        return None;
+    }
+    Some(bytes.iter().fold(0usize, |size, b| size << 7 | usize::from(*b)))
+}
+
+/// Text of a text frame, the first byte is the encoding
+fn decode_text(content: &[u8]) -> String {
+    if content.is_empty() {
+        return String::from("");
+    }
+    let bytes = &content[1..];
+    let text = match content[0] {
+        // utf-16 with byte order mark
+        1 => {
+            if bytes.len() >= 2 && bytes[0] == 0xfe && bytes[1] == 0xff {
+                utf16(&bytes[2..], true)
+            } else if bytes.len() >= 2 {
+                utf16(&bytes[2..], false)
+            } else {
+                String::from("")
+            }
+        }
+        2 => utf16(bytes, true),
+        3 => String::from_utf8_lossy(bytes).to_string(),
+        // iso-8859-1
+        _ => bytes.iter().map(|b| char::from(*b)).collect(),
+    };
+    text.trim_end_matches('\0').trim().to_string()
+}
+
+fn utf16(bytes: &[u8], big_endian: bool) -> String {
+    let units: Vec<u16> = bytes
+        .chunks(2)
+        .filter(|chunk| chunk.len() == 2)
+        .map(|chunk| {
+            if big_endian {
+                u16::from(chunk[0]) << 8 | u16::from(chunk[1])
+            } else {
+                u16::from(chunk[1]) << 8 | u16::from(chunk[0])
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: &[u8], text: &str) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        frame.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 3]);
+        frame.extend_from_slice(text.as_bytes());
+        frame
+    }
+
+    /// ID3v2.3 tag, the size in the header can be set larger than the frames
+    fn tag(frames: &[Vec<u8>], size: Option<usize>) -> Vec<u8> {
+        let body: Vec<u8> = frames.concat();
+        let size = size.unwrap_or(body.len());
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        tag.extend_from_slice(&[(size >> 21 & 0x7f) as u8, (size >> 14 & 0x7f) as u8, (size >> 7 & 0x7f) as u8, (size & 0x7f) as u8]);
+        tag.extend(body);
+        tag
+    }
+
+    #[test]
+    fn icy_titles() {
+        assert_eq!(icy_title("StreamTitle='Artist - Title';StreamUrl='';"), Some(String::from("Artist - Title")));
+        assert_eq!(icy_title("StreamTitle='Rock 'n' Roll';"), Some(String::from("Rock 'n' Roll")));
+        assert_eq!(icy_title("StreamTitle='No Separator'"), Some(String::from("No Separator")));
+        assert_eq!(icy_title("StreamTitle='';StreamUrl='';"), None);
+        assert_eq!(icy_title("StreamUrl='http://example.com';"), None);
+    }
+
+    #[test]
+    fn id3_artist_and_title() {
+        let data = tag(&[frame(b"TPE1", "Artist"), frame(b"TIT2", "Title")], None);
+        assert_eq!(id3_title(&data), Some(String::from("Artist - Title")));
+        let data = tag(&[frame(b"TIT2", "Title")], None);
+        assert_eq!(id3_title(&data), Some(String::from("Title")));
+        let data = tag(&[frame(b"TPE1", "Artist")], None);
+        assert_eq!(id3_title(&data), None);
+    }
+
+    #[test]
+    fn id3_last_tag_in_segment() {
+        let mut data = tag(&[frame(b"TIT2", "First")], None);
+        data.extend_from_slice(&[0x47; 188]);
+        data.extend(tag(&[frame(b"TIT2", "Second")], None));
+        data.extend_from_slice(&[0x47; 188]);
+        assert_eq!(id3_title(&data), Some(String::from("Second")));
+    }
+
+    #[test]
+    fn id3_truncated_tag() {
+        let data = tag(&[frame(b"TIT2", "Cut Off")], Some(4096));
+        assert_eq!(id3_title(&data), Some(String::from("Cut Off")));
+        let mut data = tag(&[frame(b"TIT2", "Cut Off Frame")], None);
+        data.truncate(data.len() - 6);
+        assert_eq!(id3_title(&data), Some(String::from("Cut Off")));
+        assert_eq!(id3_title(b"ID3\x03\x00"), None);
+        assert_eq!(id3_title(b"no tag here"), None);
+    }
+
+    #[test]
+    fn id3_utf16_text() {
+        let mut content = vec![1, 0xff, 0xfe];
+        for unit in "Title".encode_utf16() {
+            content.extend_from_slice(&unit.to_le_bytes());
+        }
+        assert_eq!(decode_text(&content), "Title");
+    }
+}
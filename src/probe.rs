@@ -0,0 +1,459 @@
+use hls_m3u8::MasterPlaylist;
+use native_tls::TlsConnector;
+use playlist_decoder;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::Duration;
+use tree_magic;
+use url::Url;
+
+use hls;
+use proxy;
+use proxy::OutboundProxy;
+
+/// Longest response header that is read
+const MAX_HEADER_BYTES: usize = 10000;
+/// Bytes read from playlists without a content length
+const DEFAULT_CONTENT_BYTES: u64 = 10000;
+/// Playlists larger than this are not parsed
+const MAX_PLAYLIST_BYTES: u64 = 1024 * 1024;
+/// Entries of a playlist that are tried until one works
+const MAX_PLAYLIST_ENTRIES: usize = 9;
+/// Start of a stream that is used to detect its format
+const DEEP_SCAN_BYTES: u64 = 50;
+
+/// H.264 profiles and levels of hls codecs attributes
+const H264_CODECS: &[&str] = &[
+    "avc1.42001e",
+    "avc1.66.30",
+    "avc1.42001f",
+    "avc1.4d001e",
+    "avc1.77.30",
+    "avc1.4d001f",
+    "avc1.4d0028",
+    "avc1.64001f",
+    "avc1.640028",
+    "avc1.640029",
+];
+
+/// A working stream found for a station url
+#[derive(Clone, Debug)]
+pub struct StreamInfo {
+    pub url: String,
+    pub codec_audio: String,
+    pub codec_video: Option<String>,
+    /// kbit/s
+    pub bitrate: u32,
+    pub hls: bool,
+}
+
+impl StreamInfo {
+    /// Audio and video codec, e.g. "AAC,H.264"
+    pub fn codec(&self) -> String {
+        match self.codec_video {
+            Some(ref video) => format!("{},{}", self.codec_audio, video),
+            None => self.codec_audio.clone(),
+        }
+    }
+}
+
+/// Status line, headers (lower case names) and body of a response
+struct Response {
+    status: u32,
+    headers: HashMap<String, String>,
+    body: Box<dyn Read>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&String> {
+        self.headers.get(name)
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.header("content-length").and_then(|length| length.trim().parse().ok())
+    }
+}
+
+/// Follow redirects and playlists of `url` to the streams, like
+/// av_stream_info does. Connections go through the proxy if it applies
+/// to the host. Retries until at least one stream works.
+pub fn check(
+    url: &str,
+    useragent: &str,
+    timeout: u32,
+    max_depth: u8,
+    retries: u8,
+    proxy: Option<&OutboundProxy>,
+) -> Vec<Result<StreamInfo, String>> {
+    let mut list = vec![];
+    for attempt in 0..retries.max(1) {
+        if attempt > 0 {
+            thread::sleep(Duration::from_secs(1));
+        }
+        list = check_url(url, useragent, timeout, max_depth, proxy);
+        if list.iter().any(|item| item.is_ok()) {
+            break;
+        }
+    }
+    list
+}
+
+fn check_url(
+    url: &str,
+    useragent: &str,
+    timeout: u32,
+    max_depth: u8,
+    proxy: Option<&OutboundProxy>,
+) -> Vec<Result<StreamInfo, String>> {
+    if max_depth == 0 {
+        return vec![Err(String::from("max depth reached"))];
+    }
+    let response = match request(url, useragent, timeout, proxy) {
+        Ok(response) => response,
+        Err(e) => return vec![Err(e)],
+    };
+    match response.status {
+        200..=299 => {}
+        300..=399 => {
+            return match response.header("location") {
+                Some(location) => check_url(&hls::join(url, location), useragent, timeout, max_depth - 1, proxy),
+                None => vec![Err(format!("redirect without location, http status code {}", response.status))],
+            };
+        }
+        status => return vec![Err(format!("illegal http status code {}", status))],
+    }
+    let content_type = match response.header("content-type") {
+        Some(content_type) => content_type.to_lowercase(),
+        None => return vec![Err(String::from("Missing content-type in http header"))],
+    };
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_string();
+    // anything with a length is not a live stream
+    if is_playlist(&mime) || response.content_length().is_some() {
+        return playlist(response, url, useragent, timeout, max_depth, proxy);
+    }
+    match stream_codec(&mime) {
+        Some(codec) => vec![Ok(stream(response, url, codec))],
+        None => vec![Err(format!("unknown content type {}", content_type))],
+    }
+}
+
+/// Send a GET request, through the proxy if it applies, and read the
+/// response header. Icecast and shoutcast servers may answer with "ICY".
+fn request(url: &str, useragent: &str, timeout: u32, proxy: Option<&OutboundProxy>) -> Result<Response, String> {
+    let url = Url::parse(url).map_err(|e| e.to_string())?;
+    let host = url.host_str().ok_or_else(|| String::from("illegal host name"))?;
+    let port = url.port_or_known_default().ok_or_else(|| String::from("port unknown"))?;
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: */*\r\nUser-Agent: {}\r\nConnection: close\r\n\r\n",
+        path, host_header, useragent
+    );
+
+    let stream = proxy::connect(proxy, host, port, Duration::from_secs(timeout.into()))?;
+    match url.scheme() {
+        "http" => send(stream, &request),
+        "https" => {
+            let connector = TlsConnector::new().map_err(|e| e.to_string())?;
+            let domain = host.trim_start_matches('[').trim_end_matches(']');
+            let stream = connector.connect(domain, stream).map_err(|e| e.to_string())?;
+            send(stream, &request)
+        }
+        scheme => Err(format!("unknown scheme {}", scheme)),
+    }
+}
+
+fn send<S: Read + Write + 'static>(mut stream: S, request: &str) -> Result<Response, String> {
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+    stream.flush().map_err(|e| e.to_string())?;
+
+    // read byte by byte, the body is read by the caller
+    let mut header = vec![];
+    let mut byte = [0u8; 1];
+    while !header.ends_with(b"\r\n\r\n") && !header.ends_with(b"\n\n") && header.len() <= MAX_HEADER_BYTES {
+        if stream.read(&mut byte).map_err(|e| e.to_string())? == 0 {
+            break;
+        }
+        header.push(byte[0]);
+    }
+    let (status, headers) = parse_header(&String::from_utf8_lossy(&header))?;
+    Ok(Response {
+        status,
+        headers,
+        body: Box::new(stream),
+    })
+}
+
+/// Status code and headers of a response header
+fn parse_header(header: &str) -> Result<(u32, HashMap<String, String>), String> {
+    let mut lines = header.lines();
+    let status_line = lines.next().unwrap_or("");
+    if !status_line.starts_with("HTTP/") && !status_line.starts_with("ICY") {
+        return Err(String::from("HTTP header missing"));
+    }
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("broken http status line: {}", status_line))?;
+    let mut headers = HashMap::new();
+    for line in lines {
+        if let Some(index) = line.find(':') {
+            headers.insert(line[..index].trim().to_lowercase(), line[index + 1..].trim().to_string());
+        }
+    }
+    Ok((status, headers))
+}
+
+fn playlist(
+    response: Response,
+    url: &str,
+    useragent: &str,
+    timeout: u32,
+    max_depth: u8,
+    proxy: Option<&OutboundProxy>,
+) -> Vec<Result<StreamInfo, String>> {
+    let length = response.content_length().unwrap_or(DEFAULT_CONTENT_BYTES).min(MAX_PLAYLIST_BYTES);
+    let mut bytes = vec![];
+    if let Err(e) = response.body.take(length).read_to_end(&mut bytes) {
+        return vec![Err(e.to_string())];
+    }
+    let content = String::from_utf8_lossy(&bytes).to_string();
+    if playlist_decoder::is_content_hls(&content) {
+        return vec![Ok(hls_stream(url, &content))];
+    }
+
+    let mut list = vec![];
+    let entries = playlist_decoder::decode(&content);
+    for entry in entries.iter().map(|entry| entry.trim()).filter(|entry| !entry.is_empty()).take(MAX_PLAYLIST_ENTRIES) {
+        let result = check_url(&hls::join(url, entry), useragent, timeout, max_depth - 1, proxy);
+        let found = result.iter().any(|item| item.is_ok());
+        list.extend(result);
+        if found {
+            break;
+        }
+    }
+    if list.is_empty() {
+        list.push(Err(String::from("Empty playlist")));
+    }
+    list
+}
+
+/// Codecs and bandwidth of the first variant of a master playlist, media
+/// playlists do not tell the codec
+fn hls_stream(url: &str, content: &str) -> StreamInfo {
+    let mut info = StreamInfo {
+        url: url.to_string(),
+        codec_audio: String::from("UNKNOWN"),
+        codec_video: None,
+        bitrate: 0,
+        hls: true,
+    };
+    if !content.contains("#EXT-X-STREAM-INF") {
+        return info;
+    }
+    if let Ok(master) = content.parse::<MasterPlaylist>() {
+        if let Some(variant) = master.stream_inf_tags().first() {
+            if let Some(codecs) = variant.codecs() {
+                let (audio, video) = hls_codecs(codecs.as_ref());
+                info.codec_audio = audio;
+                info.codec_video = video;
+            }
+            info.bitrate = (variant.bandwidth() / 1000) as u32;
+        }
+    }
+    info
+}
+
+/// The codec of the content type is replaced by the one detected from the
+/// start of the stream, if that is known.
+fn stream(mut response: Response, url: &str, codec: &str) -> StreamInfo {
+    let mut codec = codec.to_string();
+    let mut start = vec![];
+    if (&mut response.body).take(DEEP_SCAN_BYTES).read_to_end(&mut start).is_ok() {
+        let mime = tree_magic::from_u8(&start);
+        if mime != "application/octet-stream" {
+            if let Some(detected) = stream_codec(&mime) {
+                debug!("{}: detected {} instead of {}", url, detected, codec);
+                codec = detected.to_string();
+            }
+        }
+    }
+    // icy-br is sometimes sent as "128,128"
+    let bitrate = response
+        .header("icy-br")
+        .and_then(|br| br.split(',').next().and_then(|br| br.trim().parse().ok()))
+        .unwrap_or(0);
+    StreamInfo {
+        url: url.to_string(),
+        codec_audio: codec,
+        codec_video: None,
+        bitrate,
+        hls: false,
+    }
+}
+
+/// Audio and video codec from an hls codecs attribute
+fn hls_codecs(codecs: &str) -> (String, Option<String>) {
+    let audio = if codecs.contains("mp4a.40.34") {
+        "MP3"
+    } else if codecs.contains("mp4a.40.5") {
+        "AAC+"
+    } else if codecs.contains("mp4a.40.2") {
+        "AAC"
+    } else {
+        "UNKNOWN"
+    };
+    let video = if H264_CODECS.iter().any(|codec| codecs.contains(codec)) {
+        Some(String::from("H.264"))
+    } else {
+        None
+    };
+    (audio.to_string(), video)
+}
+
+fn is_playlist(mime: &str) -> bool {
+    matches!(
+        mime,
+        "application/mpegurl"
+            | "application/x-mpegurl"
+            | "audio/mpegurl"
+            | "audio/x-mpegurl"
+            | "application/vnd.apple.mpegurl"
+            | "application/vnd.apple.mpegurl.audio"
+            | "audio/x-scpls"
+            | "application/x-scpls"
+            | "application/pls+xml"
+            | "video/x-ms-asx"
+            | "video/x-ms-asf"
+            | "application/xspf+xml"
+    )
+}
+
+/// Codec names as reported by av_stream_info
+fn stream_codec(mime: &str) -> Option<&'static str> {
+    match mime {
+        "audio/mpeg" | "audio/x-mpeg" | "audio/mp3" => Some("MP3"),
+        "audio/aac" | "audio/x-aac" => Some("AAC"),
+        "audio/aacp" => Some("AAC+"),
+        "audio/ogg" | "application/ogg" | "video/ogg" => Some("OGG"),
+        "audio/flac" => Some("FLAC"),
+        "application/flv" => Some("FLV"),
+        "application/octet-stream" => Some("UNKNOWN"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Answer requests with the response for the path until the test ends
+    fn serve(responses: Vec<(&'static str, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = vec![];
+                let mut byte = [0u8; 1];
+                while !request.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let path = request.split_whitespace().nth(1).unwrap().to_string();
+                let response = responses
+                    .iter()
+                    .find(|(prefix, _)| path == *prefix)
+                    .map(|(_, response)| response.clone())
+                    .unwrap_or_else(|| b"HTTP/1.0 404 Not Found\r\n\r\n".to_vec());
+                let _ = stream.write_all(&response);
+            }
+        });
+        base
+    }
+
+    fn mp3_start() -> Vec<u8> {
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x00".to_vec();
+        data.extend_from_slice(&[0xff, 0xfb, 0x90, 0x64]);
+        data.extend_from_slice(&[0u8; 100]);
+        data
+    }
+
+    #[test]
+    fn parse_headers() {
+        let (status, headers) = parse_header("HTTP/1.1 200 OK\r\nContent-Type: audio/mpeg\r\nicy-br: 128\r\n\r\n").unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(headers.get("content-type").unwrap(), "audio/mpeg");
+        assert_eq!(headers.get("icy-br").unwrap(), "128");
+        assert_eq!(parse_header("ICY 200 OK\r\n\r\n").unwrap().0, 200);
+        assert!(parse_header("<html>").is_err());
+        assert!(parse_header("HTTP/1.0 abc\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn hls_codecs_of_master_playlists() {
+        assert_eq!(hls_codecs("mp4a.40.2"), (String::from("AAC"), None));
+        assert_eq!(hls_codecs("mp4a.40.5,avc1.4d001f"), (String::from("AAC+"), Some(String::from("H.264"))));
+        assert_eq!(hls_codecs("mp4a.40.34"), (String::from("MP3"), None));
+        assert_eq!(hls_codecs("opus"), (String::from("UNKNOWN"), None));
+
+        let master = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=96000,CODECS=\"mp4a.40.5\"\nlow.m3u8\n";
+        let info = hls_stream("http://example.com/master.m3u8", master);
+        assert_eq!((info.codec(), info.bitrate, info.hls), (String::from("AAC+"), 96, true));
+        let media = "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXTINF:10,\na.aac\n";
+        assert_eq!(hls_stream("http://example.com/media.m3u8", media).codec(), "UNKNOWN");
+    }
+
+    #[test]
+    fn playlist_and_redirect_to_stream() {
+        let mut stream = b"ICY 200 OK\r\ncontent-type: audio/mpeg\r\nicy-br: 128,128\r\n\r\n".to_vec();
+        stream.extend(mp3_start());
+        let base = serve(vec![
+            ("/listen.pls", b"HTTP/1.0 200 OK\r\nContent-Type: audio/x-scpls\r\n\r\n[playlist]\nFile1=/missing\nFile2=/redirect\nNumberOfEntries=2\n".to_vec()),
+            ("/redirect", b"HTTP/1.0 302 Found\r\nLocation: /stream\r\n\r\n".to_vec()),
+            ("/stream", stream),
+        ]);
+        let list = check(&format!("{}/listen.pls", base), "test", 5, 5, 1, None);
+        // the order of the entries is not kept by the playlist decoder,
+        // the missing one may be tried first
+        let (working, failed): (Vec<_>, Vec<_>) = list.iter().partition(|item| item.is_ok());
+        assert_eq!(working.len(), 1);
+        assert!(list.last().unwrap().is_ok());
+        assert!(failed.iter().all(|item| item.as_ref().unwrap_err() == "illegal http status code 404"));
+        let info = working[0].as_ref().unwrap();
+        assert_eq!(info.url, format!("{}/stream", base));
+        assert_eq!((info.codec(), info.bitrate, info.hls), (String::from("MP3"), 128, false));
+    }
+
+    #[test]
+    fn deep_scan_replaces_octet_stream() {
+        let mut stream = b"HTTP/1.0 200 OK\r\nContent-Type: application/octet-stream\r\n\r\n".to_vec();
+        stream.extend(mp3_start());
+        let base = serve(vec![("/stream", stream)]);
+        let list = check(&format!("{}/stream", base), "test", 5, 5, 1, None);
+        assert_eq!(list[0].as_ref().unwrap().codec(), "MP3");
+    }
+
+    #[test]
+    fn errors_of_streams() {
+        let base = serve(vec![
+            ("/html", b"HTTP/1.0 200 OK\r\nContent-Type: text/html\r\n\r\n<html>".to_vec()),
+            ("/untyped", b"HTTP/1.0 200 OK\r\n\r\n".to_vec()),
+            ("/loop", b"HTTP/1.0 301 Moved\r\nLocation: /loop\r\n\r\n".to_vec()),
+        ]);
+        let check = |path: &str| check(&format!("{}{}", base, path), "test", 5, 2, 1, None);
+        assert_eq!(check("/html")[0].as_ref().unwrap_err(), "unknown content type text/html");
+        assert_eq!(check("/untyped")[0].as_ref().unwrap_err(), "Missing content-type in http header");
+        assert_eq!(check("/loop")[0].as_ref().unwrap_err(), "max depth reached");
+    }
+}
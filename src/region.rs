@@ -0,0 +1,147 @@
+use config::RegionProxy;
+use models::RegionCheckItem;
+use probe;
+use proxy::OutboundProxy;
+
+/// Run the stream check through the proxy of every region, a region only
+/// counts as working if the stream is found and its type is understood.
+pub fn check(regions: &[RegionProxy], url: &str, useragent: &str, timeout: u32, max_depth: u8) -> Vec<RegionCheckItem> {
+    regions
+        .iter()
+        .map(|region| {
+            let mut item = RegionCheckItem {
+                region: region.region.clone(),
+                check_ok: false,
+                http_status: 0,
+                error: String::from(""),
+            };
+            let items = match OutboundProxy::new(&region.proxy, "", "", &[]) {
+                Ok(Some(proxy)) => probe::check(url, useragent, timeout, max_depth, 1, Some(&proxy)),
+                Ok(None) => vec![Err(String::from("region without proxy"))],
+                Err(e) => vec![Err(e)],
+            };
+            item.check_ok = items.iter().any(|item| item.is_ok());
+            if item.check_ok {
+                item.http_status = 200;
+            } else {
+                item.error = items
+                    .into_iter()
+                    .filter_map(|item| item.err())
+                    .next()
+                    .unwrap_or_else(|| String::from("no stream found"));
+                item.http_status = http_status(&item.error);
+                debug!("Region {} failed for {}: {}", region.region, url, item.error);
+            }
+            item
+        })
+        .collect()
+}
+
+/// Http status of a failed check, 0 if the error is not about the status
+fn http_status(error: &str) -> u32 {
+    error
+        .trim_start_matches("illegal http status code ")
+        .parse()
+        .unwrap_or(0)
+}
+
+/// Works from some vantage points, including the checker itself, but not
+/// from all of them. None without regions.
+pub fn geo_restricted(check_ok: bool, regions: &[RegionCheckItem]) -> Option<bool> {
+    if regions.is_empty() {
+        return None;
+    }
+    let working = regions.iter().filter(|region| region.check_ok).count();
+    let restricted = if check_ok { working < regions.len() } else { working > 0 };
+    Some(restricted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Proxy that accepts one CONNECT and answers the request sent through
+    /// the tunnel itself
+    fn region_proxy(region: &str, response: &'static [u8]) -> (RegionProxy, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let handle = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let read_header = |stream: &mut ::std::net::TcpStream| {
+                let mut header = vec![];
+                let mut byte = [0u8; 1];
+                while !header.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    header.push(byte[0]);
+                }
+                String::from_utf8(header).unwrap()
+            };
+            assert!(read_header(&mut stream).starts_with("CONNECT radio.example.com:80 "));
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+            assert!(read_header(&mut stream).starts_with("GET /live HTTP/1.0\r\n"));
+            stream.write_all(response).unwrap();
+        });
+        let region = RegionProxy {
+            region: region.to_string(),
+            proxy,
+        };
+        (region, handle)
+    }
+
+    fn region(region: &str, check_ok: bool) -> RegionCheckItem {
+        RegionCheckItem {
+            region: region.to_string(),
+            check_ok,
+            http_status: 0,
+            error: String::from(""),
+        }
+    }
+
+    #[test]
+    fn geo_restriction() {
+        assert_eq!(geo_restricted(true, &[]), None);
+        assert_eq!(geo_restricted(true, &[region("de", true), region("us", true)]), Some(false));
+        assert_eq!(geo_restricted(true, &[region("de", true), region("us", false)]), Some(true));
+        assert_eq!(geo_restricted(false, &[region("de", false), region("us", true)]), Some(true));
+        assert_eq!(geo_restricted(false, &[region("de", false), region("us", false)]), Some(false));
+    }
+
+    #[test]
+    fn http_status_of_errors() {
+        assert_eq!(http_status("illegal http status code 451"), 451);
+        assert_eq!(http_status("unknown content type text/html"), 0);
+    }
+
+    #[test]
+    fn regions_run_the_stream_check() {
+        let (de, de_handle) = region_proxy("de", b"ICY 200 OK\r\ncontent-type: audio/aacp\r\nicy-br: 64\r\n\r\n\x00\x00");
+        let (us, us_handle) = region_proxy("us", b"HTTP/1.0 200 OK\r\nContent-Type: text/html\r\n\r\n<html>blocked</html>");
+        let (fr, fr_handle) = region_proxy("fr", b"HTTP/1.0 451 Unavailable For Legal Reasons\r\n\r\n");
+        let items = check(&[de, us, fr], "http://radio.example.com/live", "test", 5, 3);
+        de_handle.join().unwrap();
+        us_handle.join().unwrap();
+        fr_handle.join().unwrap();
+
+        assert!(items[0].check_ok);
+        assert_eq!(items[0].http_status, 200);
+        assert!(!items[1].check_ok);
+        assert_eq!(items[1].error, "unknown content type text/html");
+        assert!(!items[2].check_ok);
+        assert_eq!(items[2].http_status, 451);
+        assert_eq!(geo_restricted(false, &items), Some(true));
+    }
+
+    #[test]
+    fn region_with_broken_proxy() {
+        let regions = vec![RegionProxy {
+            region: String::from("de"),
+            proxy: String::from("ftp://proxy-de"),
+        }];
+        let items = check(&regions, "http://example.com/stream", "test", 1, 3);
+        assert!(!items[0].check_ok);
+        assert!(items[0].error.contains("unsupported proxy scheme"), "{}", items[0].error);
+    }
+}
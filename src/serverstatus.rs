@@ -0,0 +1,359 @@
+use reqwest;
+use serde_json;
+use serde_json::Value;
+use std::io::Read;
+use url::Url;
+
+use http::HttpClient;
+use models::ServerMountItem;
+use models::ServerStatusItem;
+use stream;
+
+/// Status pages larger than this are not parsed
+const MAX_STATUS_BYTES: u64 = 1024 * 1024;
+/// /7.html is only served to browsers
+const BROWSER_USERAGENT: &str = "Mozilla/5.0 (compatible; stream-check)";
+
+/// Query the status page of the icecast or shoutcast server a stream is
+/// served from. The server is recognized from the Server and icy-notice
+/// headers of the stream, None for other servers or if no status page
+/// answered.
+pub fn check(client: &HttpClient, url: &str) -> Option<ServerStatusItem> {
+    let server = match stream::open(url, client.useragent(), client.timeout(), client.proxy()) {
+        Ok(connection) => connection.server,
+        Err(e) => {
+            debug!("Server status, unable to open {}: {}", url, e);
+            return None;
+        }
+    };
+    let lower = server.to_lowercase();
+    let server_type = if lower.contains("icecast") {
+        "icecast"
+    } else if lower.contains("shoutcast") {
+        "shoutcast"
+    } else {
+        return None;
+    };
+    let stream_url = Url::parse(url).ok()?;
+    let result = if server_type == "icecast" {
+        icecast(client, &stream_url)
+    } else {
+        shoutcast_v2(client, &stream_url).or_else(|e| {
+            debug!("Shoutcast v2 status failed, trying v1: {}", e);
+            shoutcast_v1(client, &stream_url)
+        })
+    };
+    match result {
+        Ok(mut item) => {
+            if item.version.is_empty() {
+                item.version = server.chars().take(100).collect();
+            }
+            Some(item)
+        }
+        Err(e) => {
+            debug!("No {} status for {}: {}", server_type, url, e);
+            None
+        }
+    }
+}
+
+/// Icecast lists all mounts in status-json.xsl, `source` is an object if
+/// there is only one.
+fn icecast(client: &HttpClient, stream_url: &Url) -> Result<ServerStatusItem, String> {
+    let status_url = page(stream_url, "/status-json.xsl")?;
+    let content = fetch(client, &status_url, None)?;
+    parse_icecast(stream_url, status_url, &content)
+}
+
+fn parse_icecast(stream_url: &Url, status_url: String, content: &str) -> Result<ServerStatusItem, String> {
+    let json: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let stats = json.get("icestats").ok_or_else(|| String::from("no icestats"))?;
+    let sources = match stats.get("source") {
+        Some(Value::Array(sources)) => sources.iter().collect(),
+        Some(source) => vec![source],
+        None => vec![],
+    };
+    let mut item = new_item("icecast", text(stats.get("server_id")), status_url);
+    for source in sources {
+        let listenurl = text(source.get("listenurl"));
+        let mount = Url::parse(&listenurl).map(|u| u.path().to_string()).unwrap_or_default();
+        if mount == stream_url.path() {
+            item.mount = mount;
+            item.listeners = number(source.get("listeners"));
+            item.peak_listeners = number(source.get("listener_peak"));
+            item.description = description(&[source.get("server_description"), source.get("server_name")]);
+        } else {
+            // the listenurl holds the configured hostname, which is not
+            // always reachable from outside
+            let bitrate = number(source.get("bitrate"))
+                .or_else(|| number(source.get("ice-bitrate")))
+                .or_else(|| number(source.get("audio_bitrate")).map(|bps| if bps > 10_000 { bps / 1000 } else { bps }))
+                .unwrap_or(0);
+            item.siblings.push(ServerMountItem {
+                url: sibling_url(stream_url, &mount),
+                mount,
+                content_type: text(source.get("server_type")),
+                bitrate,
+                listeners: number(source.get("listeners")),
+            });
+        }
+    }
+    found(item)
+}
+
+/// Shoutcast v2 lists all streams of the server in /statistics
+fn shoutcast_v2(client: &HttpClient, stream_url: &Url) -> Result<ServerStatusItem, String> {
+    let status_url = page(stream_url, "/statistics?json=1")?;
+    let content = fetch(client, &status_url, None)?;
+    parse_shoutcast_v2(stream_url, status_url, &content)
+}
+
+fn parse_shoutcast_v2(stream_url: &Url, status_url: String, content: &str) -> Result<ServerStatusItem, String> {
+    let json: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let streams: Vec<&Value> = match json.get("streams") {
+        Some(Value::Array(streams)) => streams.iter().collect(),
+        _ => return Err(String::from("no streams")),
+    };
+    let mut item = new_item("shoutcast", text(json.get("version")), status_url);
+    // the default stream is also served on / and /;
+    let path = stream_url.path();
+    let default_path = path == "/" || path == "/;";
+    let own = streams
+        .iter()
+        .position(|s| text(s.get("streampath")) == path)
+        .or_else(|| if default_path { streams.iter().position(|s| number(s.get("id")) == Some(1)) } else { None });
+    for (index, stream) in streams.iter().enumerate() {
+        let mount = text(stream.get("streampath"));
+        if Some(index) == own {
+            item.mount = mount;
+            item.listeners = number(stream.get("currentlisteners"));
+            item.peak_listeners = number(stream.get("peaklisteners"));
+            item.description = description(&[stream.get("servertitle")]);
+            if item.version.is_empty() {
+                item.version = text(stream.get("version"));
+            }
+        } else {
+            item.siblings.push(ServerMountItem {
+                url: sibling_url(stream_url, &mount),
+                mount,
+                content_type: text(stream.get("content")),
+                bitrate: number(stream.get("bitrate")).unwrap_or(0),
+                listeners: number(stream.get("currentlisteners")),
+            });
+        }
+    }
+    found(item)
+}
+
+/// Shoutcast v1 serves a single stream, /7.html holds
+/// `listeners,status,peak,max,unique,bitrate,title`
+fn shoutcast_v1(client: &HttpClient, stream_url: &Url) -> Result<ServerStatusItem, String> {
+    let status_url = page(stream_url, "/7.html")?;
+    let content = fetch(client, &status_url, Some(BROWSER_USERAGENT))?;
+    parse_shoutcast_v1(stream_url, status_url, &content)
+}
+
+fn parse_shoutcast_v1(stream_url: &Url, status_url: String, content: &str) -> Result<ServerStatusItem, String> {
+    let body = strip_tags(content);
+    let fields: Vec<&str> = body.trim().splitn(7, ',').collect();
+    if fields.len() < 6 {
+        return Err(format!("unexpected content: {}", body.chars().take(100).collect::<String>()));
+    }
+    let mut item = new_item("shoutcast", String::from(""), status_url);
+    item.mount = stream_url.path().to_string();
+    item.listeners = fields[0].trim().parse().ok();
+    item.peak_listeners = fields[2].trim().parse().ok();
+    Ok(item)
+}
+
+fn new_item(server_type: &str, version: String, status_url: String) -> ServerStatusItem {
+    ServerStatusItem {
+        server_type: server_type.to_string(),
+        version,
+        status_url,
+        listeners: None,
+        peak_listeners: None,
+        description: String::from(""),
+        mount: String::from(""),
+        siblings: vec![],
+    }
+}
+
+/// The status page only counts if it lists the mount of the stream
+fn found(item: ServerStatusItem) -> Result<ServerStatusItem, String> {
+    if item.mount.is_empty() {
+        Err(format!("mount not listed, {} other mounts", item.siblings.len()))
+    } else {
+        Ok(item)
+    }
+}
+
+fn page(stream_url: &Url, path: &str) -> Result<String, String> {
+    stream_url.join(path).map(|url| url.to_string()).map_err(|e| e.to_string())
+}
+
+fn sibling_url(stream_url: &Url, mount: &str) -> String {
+    stream_url.join(mount).map(|url| url.to_string()).unwrap_or_default()
+}
+
+fn fetch(client: &HttpClient, url: &str, useragent: Option<&str>) -> Result<String, String> {
+    let read = |res: reqwest::Response| {
+        if !res.status().is_success() {
+            return Err(format!("http status {} for {}", res.status(), url));
+        }
+        let mut bytes = vec![];
+        res.take(MAX_STATUS_BYTES)
+            .read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())?;
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    };
+    match useragent {
+        Some(useragent) => client.get_as(url, useragent, read),
+        None => client.get(url, read),
+    }
+}
+
+/// Numbers are sent as strings by some versions
+fn number(value: Option<&Value>) -> Option<u32> {
+    match value {
+        Some(Value::Number(n)) => n.as_u64().map(|n| n as u32),
+        Some(Value::String(s)) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn text(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(s)) => s.trim().to_string(),
+        Some(Value::Number(n)) => n.to_string(),
+        _ => String::from(""),
+    }
+}
+
+/// First set value, servers fill unset fields with placeholders
+fn description(values: &[Option<&Value>]) -> String {
+    values
+        .iter()
+        .map(|value| text(*value))
+        .find(|value| !value.is_empty() && value != "Unspecified description" && value != "Unspecified name")
+        .unwrap_or_default()
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn icecast_with_one_source() {
+        let content = r#"{"icestats":{"admin":"admin@example.org","server_id":"Icecast 2.4.4","source":
+            {"listenurl":"http://localhost:8000/live","listeners":12,"listener_peak":"40",
+             "server_description":"Unspecified description","server_name":"Radio One","server_type":"audio/mpeg"}}}"#;
+        let item = parse_icecast(&url("http://radio.example:8000/live"), String::from("status"), content).unwrap();
+        assert_eq!(item.server_type, "icecast");
+        assert_eq!(item.version, "Icecast 2.4.4");
+        assert_eq!(item.mount, "/live");
+        assert_eq!(item.listeners, Some(12));
+        assert_eq!(item.peak_listeners, Some(40));
+        assert_eq!(item.description, "Radio One");
+        assert!(item.siblings.is_empty());
+    }
+
+    #[test]
+    fn icecast_with_several_sources() {
+        let content = r#"{"icestats":{"server_id":"Icecast 2.4.4","source":[
+            {"listenurl":"http://localhost:8000/live","listeners":3,"server_description":"Live"},
+            {"listenurl":"http://localhost:8000/live.aac","listeners":"7","server_type":"audio/aac","audio_bitrate":64000},
+            {"listenurl":"http://localhost:8000/low","ice-bitrate":32,"server_type":"audio/mpeg"}]}}"#;
+        let item = parse_icecast(&url("http://radio.example:8000/live"), String::from("status"), content).unwrap();
+        assert_eq!(item.listeners, Some(3));
+        assert_eq!(item.description, "Live");
+        assert_eq!(item.siblings.len(), 2);
+        assert_eq!(item.siblings[0].url, "http://radio.example:8000/live.aac");
+        assert_eq!(item.siblings[0].content_type, "audio/aac");
+        assert_eq!(item.siblings[0].bitrate, 64);
+        assert_eq!(item.siblings[0].listeners, Some(7));
+        assert_eq!(item.siblings[1].mount, "/low");
+        assert_eq!(item.siblings[1].bitrate, 32);
+        assert_eq!(item.siblings[1].listeners, None);
+    }
+
+    #[test]
+    fn icecast_without_the_mount() {
+        let content = r#"{"icestats":{"server_id":"Icecast 2.4.4","source":{"listenurl":"http://localhost:8000/other"}}}"#;
+        assert!(parse_icecast(&url("http://radio.example:8000/live"), String::from("status"), content).is_err());
+    }
+
+    #[test]
+    fn shoutcast_v2_statistics() {
+        let content = r#"{"totalstreams":2,"version":"2.6.0.750 (posix(linux x64))","streams":[
+            {"id":1,"currentlisteners":5,"peaklisteners":9,"servertitle":"Radio Two","bitrate":"128","content":"audio/mpeg","streampath":"/stream"},
+            {"id":2,"currentlisteners":1,"bitrate":48,"content":"audio/aacp","streampath":"/aac"}]}"#;
+        let item = parse_shoutcast_v2(&url("http://radio.example:8000/;"), String::from("status"), content).unwrap();
+        assert_eq!(item.server_type, "shoutcast");
+        assert_eq!(item.version, "2.6.0.750 (posix(linux x64))");
+        assert_eq!(item.mount, "/stream");
+        assert_eq!(item.listeners, Some(5));
+        assert_eq!(item.peak_listeners, Some(9));
+        assert_eq!(item.description, "Radio Two");
+        assert_eq!(item.siblings.len(), 1);
+        assert_eq!(item.siblings[0].url, "http://radio.example:8000/aac");
+        assert_eq!(item.siblings[0].bitrate, 48);
+
+        let item = parse_shoutcast_v2(&url("http://radio.example:8000/aac"), String::from("status"), content).unwrap();
+        assert_eq!(item.mount, "/aac");
+        assert_eq!(item.siblings[0].bitrate, 128);
+    }
+
+    #[test]
+    fn shoutcast_v1_seven_html() {
+        let content = "<html><body>14,1,52,500,13,128,Artist - Title, with comma</body></html>";
+        let item = parse_shoutcast_v1(&url("http://radio.example:8000/"), String::from("status"), content).unwrap();
+        assert_eq!(item.mount, "/");
+        assert_eq!(item.listeners, Some(14));
+        assert_eq!(item.peak_listeners, Some(52));
+
+        let content = "<html><body>Invalid resource</body></html>";
+        assert!(parse_shoutcast_v1(&url("http://radio.example:8000/"), String::from("status"), content).is_err());
+    }
+
+    #[test]
+    fn strip_tags_keeps_the_text() {
+        assert_eq!(strip_tags("<html><body>1,2,<b>3</b></body></html>"), "1,2,3");
+        assert_eq!(strip_tags("no tags"), "no tags");
+    }
+
+    #[test]
+    fn description_skips_placeholders() {
+        let placeholder = Value::from("Unspecified description");
+        let name = Value::from(" Radio ");
+        assert_eq!(description(&[Some(&placeholder), Some(&name)]), "Radio");
+        assert_eq!(description(&[None, Some(&placeholder)]), "");
+    }
+
+    #[test]
+    fn numbers_as_numbers_or_strings() {
+        assert_eq!(number(Some(&Value::from(42))), Some(42));
+        assert_eq!(number(Some(&Value::from(" 42 "))), Some(42));
+        assert_eq!(number(Some(&Value::from("n/a"))), None);
+        assert_eq!(number(Some(&Value::from(-1))), None);
+        assert_eq!(number(None), None);
+    }
+}
@@ -0,0 +1,203 @@
+use std::io;
+use std::io::Read;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use models::SoakItem;
+use proxy::OutboundProxy;
+use stream;
+use stream::Connection;
+
+/// Score points lost per underrun
+const UNDERRUN_PENALTY: i32 = 5;
+/// Score points lost per disconnect
+const DISCONNECT_PENALTY: i32 = 10;
+
+/// Listen to a stream for `duration`. Gaps of more than `gap` without data
+/// count as underruns. Streams that end or fail are reconnected until the
+/// time is up, the time without connection counts as stalled. Neither
+/// connects nor reads wait past the end of `duration`.
+pub fn listen(
+    url: &str,
+    useragent: &str,
+    timeout: u32,
+    proxy: Option<&OutboundProxy>,
+    duration: Duration,
+    gap: Duration,
+) -> SoakItem {
+    let start = Instant::now();
+    let mut item = SoakItem {
+        listen_secs: 0.0,
+        underruns: 0,
+        stalled_secs: 0.0,
+        disconnects: 0,
+        reconnects: 0,
+        failed_reconnects: 0,
+        score: 0,
+        error: String::from(""),
+    };
+    let mut connection: Option<Receiver<io::Result<Vec<u8>>>> = match stream::open(url, useragent, capped(timeout, duration), proxy) {
+        Ok(connection) => Some(read_chunks(connection)),
+        Err(e) => {
+            item.listen_secs = start.elapsed().as_secs_f32();
+            item.error = e;
+            return item;
+        }
+    };
+
+    let mut stalled = Duration::from_secs(0);
+    let mut last_data = Instant::now();
+    // the time until the first data after a reconnect is no underrun
+    let mut reconnected = false;
+    while start.elapsed() < duration {
+        let remaining = duration.saturating_sub(start.elapsed());
+        let result = match connection {
+            Some(ref chunks) => match chunks.recv_timeout(remaining) {
+                Ok(result) => result.map(|chunk| chunk.len()),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => Ok(0),
+            },
+            None => {
+                match stream::open(url, useragent, capped(timeout, remaining), proxy) {
+                    Ok(new_connection) => {
+                        debug!("Reconnected after {:?}", last_data.elapsed());
+                        item.reconnects += 1;
+                        reconnected = true;
+                        connection = Some(read_chunks(new_connection));
+                    }
+                    Err(e) => {
+                        debug!("Reconnect failed: {}", e);
+                        item.failed_reconnects += 1;
+                        thread::sleep(Duration::from_secs(1).min(remaining));
+                    }
+                }
+                continue;
+            }
+        };
+        match result {
+            Ok(count) if count > 0 => {
+                let waited = last_data.elapsed();
+                if waited > gap {
+                    stalled += waited;
+                    if !reconnected {
+                        debug!("Underrun, no data for {:?}", waited);
+                        item.underruns += 1;
+                    }
+                }
+                reconnected = false;
+                last_data = Instant::now();
+            }
+            Ok(_) => {
+                debug!("Stream ended after {:?}", start.elapsed());
+                item.disconnects += 1;
+                connection = None;
+            }
+            Err(e) => {
+                debug!("Stream failed after {:?}: {}", start.elapsed(), e);
+                item.disconnects += 1;
+                item.error = e.to_string();
+                connection = None;
+            }
+        }
+    }
+    let waited = last_data.elapsed();
+    if waited > gap {
+        stalled += waited;
+    }
+
+    let listened = start.elapsed();
+    item.listen_secs = listened.as_secs_f32();
+    item.stalled_secs = stalled.as_secs_f32().min(item.listen_secs);
+    item.score = score(&item);
+    item
+}
+
+/// Read a connection on its own thread, so waiting for data can stop at
+/// the end of the soak time instead of after the read timeout. The thread
+/// ends after the end of the stream, an error, or once nobody listens.
+fn read_chunks(mut connection: Connection) -> Receiver<io::Result<Vec<u8>>> {
+    let (sender, receiver) = sync_channel(16);
+    thread::spawn(move || {
+        let mut buf = [0u8; 8192];
+        loop {
+            let result = connection.read(&mut buf).map(|count| buf[..count].to_vec());
+            let end = match result {
+                Ok(ref chunk) => chunk.is_empty(),
+                Err(_) => true,
+            };
+            if sender.send(result).is_err() || end {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Timeout in seconds, at most the remaining time rounded up
+fn capped(timeout: u32, remaining: Duration) -> u32 {
+    let remaining = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+    (remaining as u32).clamp(1, timeout.max(1))
+}
+
+/// Stability from 0 to 100: the share of the time with data, reduced for
+/// every underrun and disconnect.
+fn score(item: &SoakItem) -> u32 {
+    if item.listen_secs <= 0.0 {
+        return 0;
+    }
+    let playing = 1.0 - item.stalled_secs / item.listen_secs;
+    let score = (playing * 100.0).round() as i32
+        - item.underruns as i32 * UNDERRUN_PENALTY
+        - item.disconnects as i32 * DISCONNECT_PENALTY;
+    score.clamp(0, 100) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soak(listen_secs: f32, stalled_secs: f32, underruns: u32, disconnects: u32) -> SoakItem {
+        SoakItem {
+            listen_secs,
+            underruns,
+            stalled_secs,
+            disconnects,
+            reconnects: disconnects,
+            failed_reconnects: 0,
+            score: 0,
+            error: String::from(""),
+        }
+    }
+
+    #[test]
+    fn perfect_run_scores_100() {
+        assert_eq!(score(&soak(60.0, 0.0, 0, 0)), 100);
+    }
+
+    #[test]
+    fn stalled_time_lowers_the_score() {
+        assert_eq!(score(&soak(60.0, 15.0, 0, 0)), 75);
+        assert_eq!(score(&soak(60.0, 60.0, 0, 0)), 0);
+    }
+
+    #[test]
+    fn underruns_and_disconnects_cost_points() {
+        assert_eq!(score(&soak(60.0, 0.0, 2, 0)), 100 - 2 * UNDERRUN_PENALTY as u32);
+        assert_eq!(score(&soak(60.0, 0.0, 0, 3)), 100 - 3 * DISCONNECT_PENALTY as u32);
+        assert_eq!(score(&soak(60.0, 6.0, 1, 1)), 90 - 5 - 10);
+    }
+
+    #[test]
+    fn score_is_clamped_to_0() {
+        assert_eq!(score(&soak(60.0, 30.0, 10, 10)), 0);
+        assert_eq!(score(&soak(0.0, 0.0, 0, 0)), 0);
+    }
+
+    #[test]
+    fn timeouts_are_capped_at_the_remaining_time() {
+        assert_eq!(capped(10, Duration::from_secs(60)), 10);
+        assert_eq!(capped(10, Duration::from_millis(2500)), 3);
+        assert_eq!(capped(10, Duration::from_millis(0)), 1);
+    }
+}
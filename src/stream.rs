@@ -0,0 +1,199 @@
+use reqwest;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, USER_AGENT};
+use std::io;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use proxy::OutboundProxy;
+
+/// Servers send a burst of buffered audio right after connecting, data
+/// of this first part of the capture is not used to measure the bitrate.
+const BURST_MILLIS: u64 = 2000;
+
+/// Audio data read from a stream, without icy metadata blocks.
+pub struct Capture {
+    pub content_type: String,
+    pub data: Vec<u8>,
+    /// time it took to read the data
+    pub elapsed: Duration,
+    /// bytes read during the initial burst
+    pub burst_bytes: usize,
+    /// the server sends icy metadata
+    pub icy: bool,
+    /// icy metadata blocks in the order they were received
+    pub metadata: Vec<String>,
+}
+
+impl Capture {
+    /// Bitrate in kbit/s the server sent with after the initial burst.
+    /// None if the capture was too short to tell.
+    pub fn measured_bitrate(&self) -> Option<u32> {
+        let burst = Duration::from_millis(BURST_MILLIS);
+        if self.elapsed < burst * 2 {
+            return None;
+        }
+        let seconds = (self.elapsed - burst).as_secs_f64();
+        let bytes = self.data.len().saturating_sub(self.burst_bytes) as f64;
+        Some((bytes * 8.0 / seconds / 1000.0).round() as u32)
+    }
+}
+
+/// An open stream, reading returns the audio without icy metadata
+pub struct Connection {
+    pub content_type: String,
+    /// the Server header and icy notices, identify icecast and shoutcast
+    pub server: String,
+    reader: IcyReader<reqwest::Response>,
+}
+
+impl Connection {
+    pub fn icy(&self) -> bool {
+        self.reader.metaint > 0
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Connect to a stream, icy metadata is requested and stripped from the
+/// audio. `timeout` applies to the connect and every single read.
+pub fn open(url: &str, useragent: &str, timeout: u32, proxy: Option<&OutboundProxy>) -> Result<Connection, String> {
+    let res = client(useragent, timeout, proxy)?
+        .get(url)
+        .header("Icy-MetaData", "1")
+        .send()
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("http status {}", res.status()));
+    }
+    let header = |name: &str| {
+        res.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    };
+    let content_type = header(CONTENT_TYPE.as_str()).unwrap_or_default();
+    let metaint: usize = header("icy-metaint").and_then(|v| v.trim().parse().ok()).unwrap_or(0);
+    let server = ["server", "icy-notice1", "icy-notice2"]
+        .iter()
+        .filter_map(|name| header(name))
+        .collect::<Vec<String>>()
+        .join(" ");
+    Ok(Connection {
+        content_type,
+        server,
+        reader: IcyReader::new(res, metaint),
+    })
+}
+
+/// Client for stream requests, `timeout` applies to the connect and every
+/// single read. Requests go through `proxy` unless it excludes the host.
+pub fn client(useragent: &str, timeout: u32, proxy: Option<&OutboundProxy>) -> Result<reqwest::Client, String> {
+    let mut builder = builder(useragent, timeout)?;
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.reqwest()?);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+fn builder(useragent: &str, timeout: u32) -> Result<reqwest::ClientBuilder, String> {
+    let mut headers = HeaderMap::new();
+    headers.insert(USER_AGENT, HeaderValue::from_str(useragent).map_err(|e| e.to_string())?);
+    Ok(reqwest::Client::builder()
+        .default_headers(headers)
+        .timeout(Duration::from_secs(timeout.into())))
+}
+
+/// Read `duration` worth of a stream (wall clock time), at most
+/// `max_bytes`.
+pub fn capture(
+    url: &str,
+    useragent: &str,
+    timeout: u32,
+    proxy: Option<&OutboundProxy>,
+    duration: Duration,
+    max_bytes: usize,
+) -> Result<Capture, String> {
+    let mut connection = open(url, useragent, timeout, proxy)?;
+    let start = Instant::now();
+    let mut data = vec![];
+    let mut burst_bytes = 0;
+    let mut buf = [0u8; 8192];
+    while start.elapsed() < duration && data.len() < max_bytes {
+        let count = connection.read(&mut buf).map_err(|e| e.to_string())?;
+        if count == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..count]);
+        if start.elapsed() < Duration::from_millis(BURST_MILLIS) {
+            burst_bytes = data.len();
+        }
+    }
+    data.truncate(max_bytes);
+    Ok(Capture {
+        icy: connection.icy(),
+        content_type: connection.content_type,
+        data,
+        elapsed: start.elapsed(),
+        burst_bytes,
+        metadata: connection.reader.metadata,
+    })
+}
+
+/// Removes icy metadata from a shoutcast/icecast stream. After every
+/// `metaint` bytes of audio there is a length byte (in 16 byte blocks)
+/// followed by the metadata.
+struct IcyReader<R: Read> {
+    inner: R,
+    metaint: usize,
+    /// audio bytes left until the next metadata block
+    remaining: usize,
+    metadata: Vec<String>,
+}
+
+impl<R: Read> IcyReader<R> {
+    fn new(inner: R, metaint: usize) -> IcyReader<R> {
+        IcyReader {
+            inner,
+            metaint,
+            remaining: metaint,
+            metadata: vec![],
+        }
+    }
+
+    /// Returns false at the end of the stream
+    fn read_metadata(&mut self) -> io::Result<bool> {
+        let mut length = [0u8; 1];
+        if self.inner.read(&mut length)? == 0 {
+            return Ok(false);
+        }
+        let mut block = vec![0u8; usize::from(length[0]) * 16];
+        self.inner.read_exact(&mut block)?;
+        let text = String::from_utf8_lossy(&block).trim_end_matches('\0').to_string();
+        if !text.is_empty() {
+            self.metadata.push(text);
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for IcyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.metaint == 0 {
+            return self.inner.read(buf);
+        }
+        if self.remaining == 0 {
+            if !self.read_metadata()? {
+                return Ok(0);
+            }
+            self.remaining = self.metaint;
+        }
+        let max = buf.len().min(self.remaining);
+        let count = self.inner.read(&mut buf[..max])?;
+        self.remaining -= count;
+        Ok(count)
+    }
+}
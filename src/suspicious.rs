@@ -0,0 +1,169 @@
+use std::net::IpAddr;
+use url::Url;
+
+use homepage;
+use models::StationCheckItemNew;
+use models::StationItem;
+
+/// Second level labels of country domains, e.g. co.uk or com.br, where
+/// the registrable domain has three labels.
+const COUNTRY_SECOND_LEVEL: &[&str] = &["ac", "co", "com", "edu", "gov", "net", "or", "org"];
+
+/// Look for signs that a working stream is not the stream of the station
+/// anymore, e.g. because its domain expired and was taken over. Returns the
+/// reason if the check result is suspicious: the stream moved to a parking
+/// host, or to another domain together with a codec or bitrate change. A
+/// bare domain change (e.g. a new CDN) is only logged and accepted. The
+/// stream is compared with UrlCache, suspicious results never replace it,
+/// so it stays the last accepted resolution while the station is marked.
+pub fn check(old: &StationItem, new: &StationCheckItemNew) -> Option<String> {
+    if !new.check_ok {
+        return None;
+    }
+    let new_host = host_of(&new.url)?;
+    if homepage::is_parking_host(&new_host) {
+        return Some(format!("parking host {}", new_host));
+    }
+
+    // nothing accepted yet, or cleared by an operator
+    if old.urlcache.is_empty() {
+        return None;
+    }
+    let old_host = host_of(&old.urlcache)?;
+    let new_domain = base_domain(&new_host);
+    if new_domain == base_domain(&old_host) {
+        return None;
+    }
+    // redirects back to the domain of the station url are expected
+    if host_of(&old.url).map(|h| base_domain(&h)) == Some(new_domain.clone()) {
+        return None;
+    }
+    let format_changed = old.codec != new.codec || (old.bitrate > 0 && old.bitrate != new.bitrate);
+    if format_changed {
+        return Some(format!(
+            "host {} -> {} with codec/bitrate {}/{} -> {}/{}",
+            old_host, new_host, old.codec, old.bitrate, new.codec, new.bitrate
+        ));
+    }
+    warn!("Stream of {} moved to another domain: {} -> {}", old.uuid, old_host, new_host);
+    None
+}
+
+fn host_of(url: &str) -> Option<String> {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.trim_end_matches('.').to_lowercase()))
+}
+
+/// Registrable part of a host name (example.com for a.b.example.com),
+/// ip addresses are returned unchanged.
+fn base_domain(host: &str) -> String {
+    if host.parse::<IpAddr>().is_ok() || host.starts_with('[') {
+        return host.to_string();
+    }
+    let labels: Vec<&str> = host.split('.').collect();
+    let count = if labels.len() >= 3
+        && labels[labels.len() - 1].len() == 2
+        && COUNTRY_SECOND_LEVEL.contains(&labels[labels.len() - 2])
+    {
+        3
+    } else {
+        2
+    };
+    let start = labels.len().saturating_sub(count);
+    labels[start..].join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn station(url: &str, urlcache: &str) -> StationItem {
+        StationItem {
+            id: 1,
+            uuid: String::from("station"),
+            name: String::from("Radio"),
+            url: String::from(url),
+            codec: String::from("MP3"),
+            bitrate: 128,
+            hls: false,
+            check_ok: true,
+            urlcache: String::from(urlcache),
+            favicon: String::from(""),
+            favicon_mirror: String::from(""),
+            homepage: String::from(""),
+        }
+    }
+
+    fn result(url: &str, codec: &str, bitrate: i32) -> StationCheckItemNew {
+        StationCheckItemNew {
+            url: String::from(url),
+            codec: String::from(codec),
+            bitrate,
+            check_ok: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parking_hosts_are_suspicious() {
+        let old = station("http://radio.example/live", "http://stream.radio.example/live");
+        let reason = check(&old, &result("http://ww1.sedoparking.com/", "MP3", 128));
+        assert_eq!(reason, Some(String::from("parking host ww1.sedoparking.com")));
+        // also without an accepted url
+        let old = station("http://radio.example/live", "");
+        assert!(check(&old, &result("http://sedo.com/", "MP3", 128)).is_some());
+    }
+
+    #[test]
+    fn new_domain_with_new_format_is_suspicious() {
+        let old = station("http://radio.example/live", "http://stream.radio.example/live");
+        let reason = check(&old, &result("http://other.example/ads", "AAC", 64));
+        assert_eq!(reason, Some(String::from("host stream.radio.example -> other.example with codec/bitrate MP3/128 -> AAC/64")));
+    }
+
+    #[test]
+    fn new_domain_alone_is_accepted() {
+        let old = station("http://radio.example/live", "http://stream.radio.example/live");
+        assert_eq!(check(&old, &result("http://edge.cdn.example/radio", "MP3", 128)), None);
+    }
+
+    #[test]
+    fn same_domain_and_station_domain_are_accepted() {
+        let old = station("http://radio.example/live", "http://cdn.example/radio");
+        assert_eq!(check(&old, &result("http://edge2.cdn.example/radio", "AAC", 64)), None);
+        assert_eq!(check(&old, &result("http://radio.example/live.aac", "AAC", 64)), None);
+    }
+
+    #[test]
+    fn failed_checks_and_first_checks_are_not_compared() {
+        let old = station("http://radio.example/live", "http://stream.radio.example/live");
+        let mut failed = result("http://other.example/ads", "AAC", 64);
+        failed.check_ok = false;
+        assert_eq!(check(&old, &failed), None);
+        let old = station("http://radio.example/live", "");
+        assert_eq!(check(&old, &result("http://other.example/ads", "AAC", 64)), None);
+    }
+
+    #[test]
+    fn base_domain_of_hosts() {
+        assert_eq!(base_domain("stream.example.com"), "example.com");
+        assert_eq!(base_domain("a.b.example.com"), "example.com");
+        assert_eq!(base_domain("example.com"), "example.com");
+        assert_eq!(base_domain("localhost"), "localhost");
+    }
+
+    #[test]
+    fn base_domain_of_country_second_level() {
+        assert_eq!(base_domain("radio.bbc.co.uk"), "bbc.co.uk");
+        assert_eq!(base_domain("stream.radio.com.br"), "radio.com.br");
+        // only for two letter top level domains
+        assert_eq!(base_domain("a.co.example"), "co.example");
+    }
+
+    #[test]
+    fn base_domain_of_addresses() {
+        assert_eq!(base_domain("192.168.1.10"), "192.168.1.10");
+        assert_eq!(base_domain("[2001:db8::1]"), "[2001:db8::1]");
+    }
+}
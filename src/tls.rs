@@ -0,0 +1,105 @@
+use openssl::asn1::Asn1Time;
+use openssl::nid::Nid;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::{X509NameRef, X509VerifyResult};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+use models::TlsItem;
+use proxy;
+use proxy::OutboundProxy;
+
+/// X509_V_ERR_HOSTNAME_MISMATCH
+const HOSTNAME_MISMATCH: i32 = 62;
+
+/// Inspect the certificate of an https url. The handshake is done with
+/// verification errors recorded instead of failing, so the certificate
+/// of servers with broken chains can be inspected as well.
+/// Returns None for other schemes.
+pub fn inspect(url: &str, timeout: u32, proxy: Option<&OutboundProxy>) -> Option<TlsItem> {
+    let url = Url::parse(url).ok()?;
+    if url.scheme() != "https" {
+        return None;
+    }
+    let host = url.host_str()?.to_string();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let mut item = TlsItem {
+        host: host.clone(),
+        status: String::from("handshake_failed"),
+        issuer: String::from(""),
+        subject: String::from(""),
+        not_after: String::from(""),
+        expires_in_secs: 0,
+        hostname_match: false,
+        chain_valid: false,
+        error: String::from(""),
+    };
+    match handshake(&host, port, timeout, proxy, &mut item) {
+        Ok(()) => {}
+        Err(e) => {
+            debug!("TLS handshake with {}:{} failed: {}", host, port, e);
+            item.error = e;
+        }
+    }
+    Some(item)
+}
+
+fn handshake(host: &str, port: u16, timeout: u32, proxy: Option<&OutboundProxy>, item: &mut TlsItem) -> Result<(), String> {
+    let stream = proxy::connect(proxy, host, port, Duration::from_secs(timeout.into()))?;
+
+    let errors: Arc<Mutex<Vec<X509VerifyResult>>> = Arc::new(Mutex::new(vec![]));
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+    let recorded = Arc::clone(&errors);
+    builder.set_verify_callback(SslVerifyMode::PEER, move |ok, ctx| {
+        if !ok {
+            recorded.lock().unwrap().push(ctx.error());
+        }
+        true
+    });
+    let connector = builder.build();
+    let ssl = connector
+        .connect(host, stream)
+        .map_err(|e| e.to_string())?;
+    let cert = ssl
+        .ssl()
+        .peer_certificate()
+        .ok_or_else(|| String::from("no peer certificate"))?;
+
+    let errors = errors.lock().unwrap();
+    item.hostname_match = !errors.iter().any(|e| e.as_raw() == HOSTNAME_MISMATCH);
+    item.chain_valid = !errors.iter().any(|e| e.as_raw() != HOSTNAME_MISMATCH);
+    item.error = errors
+        .iter()
+        .map(|e| e.error_string())
+        .collect::<Vec<&str>>()
+        .join(", ");
+    item.issuer = name_string(cert.issuer_name());
+    item.subject = name_string(cert.subject_name());
+    item.not_after = cert.not_after().to_string();
+    let now = Asn1Time::days_from_now(0).map_err(|e| e.to_string())?;
+    let diff = now.diff(cert.not_after()).map_err(|e| e.to_string())?;
+    item.expires_in_secs = i64::from(diff.days) * 86400 + i64::from(diff.secs);
+
+    item.status = if item.expires_in_secs <= 0 {
+        String::from("expired")
+    } else if errors.is_empty() {
+        String::from("valid")
+    } else {
+        // browsers refuse the connection, players with verification
+        // disabled still work
+        String::from("insecure_only")
+    };
+    Ok(())
+}
+
+/// Common name and organization of a certificate name
+fn name_string(name: &X509NameRef) -> String {
+    let mut parts = vec![];
+    for nid in &[Nid::COMMONNAME, Nid::ORGANIZATIONNAME] {
+        for entry in name.entries_by_nid(*nid) {
+            parts.push(String::from_utf8_lossy(entry.data().as_slice()).to_string());
+        }
+    }
+    parts.join(", ")
+}
@@ -0,0 +1,251 @@
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use db;
+use std;
+use mysql;
+use models::StationCheckItemNew;
+
+/// Collects check results from the check workers and writes them to the
+/// database in batches, either when `batch_size` results are queued or
+/// when `flush_interval` has passed since the first unwritten result.
+///
+/// The queue between workers and writer is bounded, so workers block on
+/// `send` if the database can not keep up.
+pub struct CheckWriter {
+    sender: Option<SyncSender<Message>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+enum Message {
+    Check(Box<StationCheckItemNew>),
+    /// write everything queued so far, then acknowledge
+    Flush(Sender<()>),
+}
+
+/// Queues check results for the writer
+#[derive(Clone)]
+pub struct CheckSender(SyncSender<Message>);
+
+impl CheckSender {
+    /// Blocks if the queue is full, fails if the writer is gone
+    pub fn send(&self, item: StationCheckItemNew) -> Result<(), ()> {
+        self.0.send(Message::Check(Box::new(item))).map_err(|_| ())
+    }
+}
+
+impl CheckWriter {
+    pub fn new(
+        database_url: &str,
+        batch_size: usize,
+        flush_interval: Duration,
+        queue_size: usize,
+    ) -> CheckWriter {
+        let (sender, receiver) = sync_channel(queue_size);
+        let database_url = String::from(database_url);
+        let handle = thread::spawn(move || {
+            let mut pool: Option<mysql::Pool> = None;
+            write_loop(receiver, batch_size, flush_interval, |batch| {
+                save(&database_url, &mut pool, batch)
+            });
+        });
+        CheckWriter {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn sender(&self) -> CheckSender {
+        CheckSender(self.sender.clone().expect("writer already finished"))
+    }
+
+    /// Write all queued results and wait until they are in the database,
+    /// so the next batch of stations does not contain them again.
+    pub fn flush(&self, timeout: Duration) {
+        let (ack, done) = channel();
+        let sent = match self.sender {
+            Some(ref sender) => sender.send(Message::Flush(ack)).is_ok(),
+            None => false,
+        };
+        if !sent || done.recv_timeout(timeout).is_err() {
+            error!("Check writer did not flush in time");
+        }
+    }
+
+    /// Close the queue and wait up to `timeout` until all queued results
+    /// are written. Checks that are still running keep their senders, their
+    /// results are lost if they do not finish in time.
+    pub fn finish(mut self, timeout: Duration) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let deadline = Instant::now() + timeout;
+            while !handle.is_finished() {
+                if Instant::now() >= deadline {
+                    error!("Check writer did not finish in time");
+                    return;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            if handle.join().is_err() {
+                error!("Check writer thread panicked");
+            }
+        }
+    }
+}
+
+/// Collect results into batches and hand every batch to `save`
+fn write_loop<F>(receiver: Receiver<Message>, batch_size: usize, flush_interval: Duration, mut save: F)
+where
+    F: FnMut(&[StationCheckItemNew]),
+{
+    let mut batch: Vec<StationCheckItemNew> = vec![];
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let received = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if deadline > now {
+                    receiver.recv_timeout(deadline - now)
+                } else {
+                    Err(RecvTimeoutError::Timeout)
+                }
+            }
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(Message::Flush(ack)) => {
+                flush(&mut save, &mut batch);
+                deadline = None;
+                let _ = ack.send(());
+            }
+            Ok(Message::Check(item)) => {
+                if batch.is_empty() {
+                    deadline = Some(Instant::now() + flush_interval);
+                }
+                batch.push(*item);
+                if batch.len() >= batch_size {
+                    flush(&mut save, &mut batch);
+                    deadline = None;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                flush(&mut save, &mut batch);
+                deadline = None;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                flush(&mut save, &mut batch);
+                return;
+            }
+        }
+    }
+}
+
+fn flush<F>(save: &mut F, batch: &mut Vec<StationCheckItemNew>)
+where
+    F: FnMut(&[StationCheckItemNew]),
+{
+    if !batch.is_empty() {
+        save(batch);
+        batch.clear();
+    }
+}
+
+fn save(database_url: &str, pool: &mut Option<mysql::Pool>, batch: &[StationCheckItemNew]) {
+    let result = db::retry("Save checks", db::RETRY_ATTEMPTS, || {
+        if pool.is_none() {
+            *pool = Some(db::new(database_url)?);
+        }
+        match *pool {
+            Some(ref pool) => db::save_checks(pool, batch),
+            None => Ok(()),
+        }
+    });
+    if let Err(err) = result {
+        error!("Save checks error, writing {} check results one by one: {}", batch.len(), err);
+        if let Some(ref pool) = *pool {
+            save_each(pool, batch);
+        }
+    }
+}
+
+/// Write the results of a failed batch one at a time, so a single bad
+/// row only loses its own result.
+fn save_each(pool: &mysql::Pool, batch: &[StationCheckItemNew]) {
+    let mut dropped = 0;
+    for item in batch {
+        if let Err(err) = db::save_checks(pool, std::slice::from_ref(item)) {
+            error!("Save check error, dropping check result of {}: {}", item.station_uuid, err);
+            dropped += 1;
+        }
+    }
+    if dropped > 0 {
+        error!("Dropped {} of {} check results", dropped, batch.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(station_uuid: &str) -> Message {
+        Message::Check(Box::new(StationCheckItemNew {
+            station_uuid: String::from(station_uuid),
+            ..Default::default()
+        }))
+    }
+
+    /// Run the write loop on a thread and report every batch it saves
+    fn start(batch_size: usize, flush_interval: Duration) -> (SyncSender<Message>, Receiver<Vec<String>>) {
+        let (sender, receiver) = sync_channel(10);
+        let (saved, batches) = channel();
+        thread::spawn(move || {
+            write_loop(receiver, batch_size, flush_interval, |batch| {
+                let uuids = batch.iter().map(|item| item.station_uuid.clone()).collect();
+                saved.send(uuids).unwrap();
+            });
+        });
+        (sender, batches)
+    }
+
+    #[test]
+    fn full_batches_are_saved_at_once() {
+        let (sender, batches) = start(2, Duration::from_secs(60));
+        for uuid in &["a", "b", "c"] {
+            sender.send(check(uuid)).unwrap();
+        }
+        let batch = batches.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(batch, vec!["a", "b"]);
+        assert!(batches.recv_timeout(Duration::from_millis(200)).is_err());
+
+        drop(sender);
+        let batch = batches.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(batch, vec!["c"]);
+    }
+
+    #[test]
+    fn partial_batches_are_saved_after_the_interval() {
+        let (sender, batches) = start(100, Duration::from_millis(200));
+        let start = Instant::now();
+        sender.send(check("a")).unwrap();
+        sender.send(check("b")).unwrap();
+        let batch = batches.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(batch, vec!["a", "b"]);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        sender.send(check("c")).unwrap();
+        let batch = batches.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(batch, vec!["c"]);
+    }
+
+    #[test]
+    fn flush_saves_the_queued_results() {
+        let (sender, batches) = start(100, Duration::from_secs(60));
+        sender.send(check("a")).unwrap();
+        let (ack, done) = channel();
+        sender.send(Message::Flush(ack)).unwrap();
+        done.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(batches.try_recv().unwrap(), vec!["a"]);
+    }
+}